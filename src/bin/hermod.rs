@@ -1,15 +1,25 @@
+use hermod::certificate::{self, Certificate};
 use hermod::cli;
-use hermod::config::ClientConfigBuilder;
+use hermod::config::{
+    ClientConfigBuilder, ClientSettings, ServerSettings, SERVER_CONFIG, SERVER_SETTINGS,
+};
 use hermod::consts::*;
+use hermod::error::{HermodError, HermodErrorKind};
+use hermod::filter::Filter;
+use hermod::identity::{self, AccessHours, Identity};
+use hermod::passphrase;
 use hermod::request::RequestMethod;
 use hermod::server::HermodServer;
+use hermod::share_key::ShareKeyOptions;
 
 use std::fs::File;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Utc};
+use clap::value_t;
 use daemonize::Daemonize;
 use log::{error, info};
+use zeroize::Zeroizing;
 
 fn main() {
     let args = cli::get_matches();
@@ -18,16 +28,47 @@ fn main() {
         ("server", Some(server_args)) => start_server(&server_args),
         ("upload", Some(req_args)) => exec_request(&req_args, RequestMethod::Upload),
         ("download", Some(req_args)) => exec_request(&req_args, RequestMethod::Download),
+        ("mkdir", Some(req_args)) => exec_request(&req_args, RequestMethod::Mkdir),
+        ("rm", Some(req_args)) if req_args.is_present("recursive") => {
+            exec_request(&req_args, RequestMethod::RemoveAll)
+        }
+        ("rm", Some(req_args)) => exec_request(&req_args, RequestMethod::Remove),
+        ("mv", Some(req_args)) => exec_request(&req_args, RequestMethod::Rename),
+        ("stat", Some(req_args)) => exec_request(&req_args, RequestMethod::Stat),
+        ("shell", Some(shell_args)) => shell(&shell_args),
         ("gen-key", Some(gen_args)) => gen_key(&gen_args),
         ("share-key", Some(sk_args)) => share_key(&sk_args),
+        ("passphrase", Some(pp_args)) => passphrase(&pp_args),
+        ("rotate-key", Some(rk_args)) => rotate_key(&rk_args),
+        ("psk", Some(psk_args)) => psk(&psk_args),
+        ("ca", Some(ca_args)) => ca(&ca_args),
+        ("certificate", Some(cert_args)) => certificate(&cert_args),
         _ => {}
     }
 }
 
 fn start_server(args: &clap::ArgMatches) {
-    // Setup logging
     let daemonize = !args.is_present("no-daemon");
-    let verbosity = args.occurrences_of("verbosity");
+
+    let settings = match server_settings(args) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let problems = settings.validate();
+    if let ("check-config", Some(_)) = args.subcommand() {
+        if problems.is_empty() {
+            println!("Configuration OK");
+            return;
+        }
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        std::process::exit(1);
+    }
 
     let base_dir: PathBuf = [
         dirs::home_dir().expect("Failed to get home_directory"),
@@ -37,10 +78,13 @@ fn start_server(args: &clap::ArgMatches) {
     .collect();
 
     if !base_dir.exists() {
-        std::fs::create_dir(base_dir).expect("Failed to create Hermods base directory");
+        hermod::permissions::create_private_dir(&base_dir)
+            .expect("Failed to create Hermods base directory");
     }
 
-    match hermod::log::setup_logger(!daemonize, verbosity) {
+    // Setup logging
+    let level = settings.log_level().unwrap_or(log::LevelFilter::Info);
+    match hermod::log::setup_logger(!daemonize, level, &settings.log_file) {
         Ok(()) => (),
         Err(e) => {
             eprintln!("Failed to initate logging, aborting. ({})", e);
@@ -48,25 +92,105 @@ fn start_server(args: &clap::ArgMatches) {
         }
     }
 
+    *SERVER_SETTINGS.write().unwrap() = settings.clone();
+
     match args.subcommand() {
         ("setup", Some(args)) => {
             info!("Generating new static files for the server...");
             let force = args.is_present("force");
-            HermodServer::setup(force);
+            let passphrase = match new_passphrase(args) {
+                Ok(passphrase) => passphrase,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            HermodServer::setup(force, passphrase.as_deref().map(String::as_str));
+        }
+        ("passphrase", Some(_)) => {
+            let res = passphrase::read_new().and_then(|passphrase| {
+                HermodServer::set_passphrase(passphrase.as_deref().map(String::as_str))
+            });
+            match res {
+                Ok(()) => println!("Updated the passphrase of the server key"),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
         }
         ("list", Some(_)) => {
             HermodServer::list_known_clients();
         }
+        ("invite", Some(args)) => {
+            if let Err(e) = invite(args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        ("pair", Some(args)) => {
+            if let Err(e) = pair(args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        ("rotate-key", Some(args)) => {
+            let days = if args.is_present("grace") {
+                value_t!(args, "grace", i64).unwrap_or_else(|e| e.exit())
+            } else {
+                HERMOD_KEY_GRACE_PERIOD
+            };
+            let force = args.is_present("force");
+            match HermodServer::rotate_key(chrono::Duration::days(days), force) {
+                Ok(()) => println!(
+                    "Rotated the server key, the previous key is accepted for {} days. Restart the server to use the new key",
+                    days
+                ),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("fingerprint", Some(_)) => {
+            if let Err(e) = HermodServer::print_fingerprint() {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        ("pending", Some(args)) => {
+            if let Err(e) = manage_pending(args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        ("clients", Some(args)) => {
+            if let Err(e) = manage_clients(args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
         // Treat all other cases as wanting to run the server
         _ => {
+            if !problems.is_empty() {
+                for problem in &problems {
+                    error!("{}", problem);
+                }
+                error!("Invalid server configuration, aborting");
+                return;
+            }
+
+            // Unlock the private key while the terminal is still available to ask for a passphrase
+            lazy_static::initialize(&SERVER_CONFIG);
+
             // Move this to HermodServer?
             if daemonize {
                 info!("Preparing to run server as a daemon");
                 let stdout = File::create("/tmp/hermod.out").unwrap();
                 let stderr = File::create("/tmp/hermod.err").unwrap();
                 let daemon = Daemonize::new()
-                    .pid_file("/tmp/hermod.pid")
-                    .working_directory(dirs::home_dir().expect("Could not find the home directory"))
+                    .pid_file(&settings.pid_file)
+                    .working_directory(&settings.storage_root)
                     .stdout(stdout)
                     .stderr(stderr);
 
@@ -78,23 +202,196 @@ fn start_server(args: &clap::ArgMatches) {
                     }
                 }
             } else {
-                std::env::set_current_dir(dirs::home_dir().expect("Failed to read home directory"))
+                std::env::set_current_dir(&settings.storage_root)
                     .expect("Failed to set current working directory");
             }
 
-            let ip = args.value_of("ip").unwrap();
-            let socket_addr = SocketAddr::new(ip.parse().unwrap(), hermod::consts::HERMOD_PORT);
             info!("Starting server");
-            HermodServer::run_server(socket_addr);
+            HermodServer::run_server(&settings);
         }
     }
 }
 
+/// Read the server configuration file and apply any overrides given on the command line
+fn server_settings(args: &clap::ArgMatches) -> Result<ServerSettings, HermodError> {
+    let mut settings = ServerSettings::load(args.value_of("config").map(Path::new))?;
+
+    let invalid = |arg: &str, value: &str| {
+        HermodError::new(HermodErrorKind::Config(format!(
+            "invalid value '{}' for --{}",
+            value, arg
+        )))
+    };
+
+    if let Some(ips) = args.values_of("ip") {
+        settings.bind = ips
+            .map(|ip| ip.parse().map_err(|_| invalid("ip", ip)))
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(port) = args.value_of("port") {
+        settings.port = port.parse().map_err(|_| invalid("port", port))?;
+    }
+    if let Some(max) = args.value_of("max-connections") {
+        settings.max_connections = max.parse().map_err(|_| invalid("max-connections", max))?;
+    }
+    if let Some(dir) = args.value_of("storage-root") {
+        settings.storage_root = dir.into();
+    }
+    if let Some(file) = args.value_of("log-file") {
+        settings.log_file = file.into();
+    }
+    if let Some(file) = args.value_of("pid-file") {
+        settings.pid_file = file.into();
+    }
+    if let Some(file) = args.value_of("passphrase-file") {
+        settings.passphrase_file = Some(file.into());
+    }
+    match args.occurrences_of("verbosity") {
+        0 => (),
+        1 => settings.log_level = "debug".to_string(),
+        _ => settings.log_level = "trace".to_string(),
+    }
+
+    Ok(settings)
+}
+
+fn manage_clients(args: &clap::ArgMatches) -> Result<(), HermodError> {
+    match args.subcommand() {
+        ("add", Some(args)) => {
+            let id_token = args.value_of("id").unwrap();
+            let key = base64::decode(args.value_of("key").unwrap())?;
+            let mut id = Identity::new(id_token.to_owned(), key)
+                .with_label(args.value_of("label").unwrap_or_default());
+            let (not_before, not_after, access_hours) = client_limits(args)?;
+            id.not_before = not_before;
+            id.not_after = not_after;
+            id.access_hours = access_hours;
+            identity::add_client(id)?;
+            println!("Added client {}", id_token);
+        }
+        ("remove", Some(args)) => {
+            let id = identity::remove_client(args.value_of("client").unwrap())?;
+            println!("Removed client {}", id.id_token);
+        }
+        ("rename", Some(args)) => {
+            let label = args.value_of("label").unwrap();
+            identity::rename_client(args.value_of("client").unwrap(), label)?;
+        }
+        ("limit", Some(args)) => {
+            let (not_before, not_after, access_hours) = client_limits(args)?;
+            let client = args.value_of("client").unwrap();
+            identity::limit_client(client, not_before, not_after, access_hours)?;
+        }
+        ("show", Some(args)) => identity::show_client(args.value_of("client").unwrap())?,
+        ("psk", Some(args)) => {
+            let client = args.value_of("client").unwrap();
+            if args.is_present("remove") {
+                let id = identity::set_psk(client, None)?;
+                println!("Removed the pre-shared key of {}", id.id_token);
+            } else {
+                let psk = hermod::genkey::gen_psk();
+                let id = identity::set_psk(client, Some(psk.clone()))?;
+                println!(
+                    "The pre-shared key of {} is {}",
+                    id.id_token,
+                    base64::encode(&psk)
+                );
+                println!("Run 'hermod psk REMOTE' on the client and enter the key");
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+type ClientLimits = (
+    Option<DateTime<Utc>>,
+    Option<DateTime<Utc>>,
+    Vec<AccessHours>,
+);
+
+/// Read the limits of when a client may connect from --not-before, --not-after and --hours
+fn client_limits(args: &clap::ArgMatches) -> Result<ClientLimits, HermodError> {
+    let time = |arg: &str| args.value_of(arg).map(identity::parse_time).transpose();
+    let access_hours = args
+        .values_of("hours")
+        .unwrap_or_default()
+        .map(|hours| {
+            hours
+                .parse()
+                .map_err(|e| HermodError::new(HermodErrorKind::InvalidClient(e)))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((time("not-before")?, time("not-after")?, access_hours))
+}
+
+fn manage_pending(args: &clap::ArgMatches) -> Result<(), HermodError> {
+    match args.subcommand() {
+        ("list", Some(_)) => identity::print_pending_clients(),
+        ("approve", Some(args)) => {
+            let id = identity::approve_pending(
+                args.value_of("client").unwrap(),
+                args.value_of("label"),
+            )?;
+            println!("Approved client {}", id.id_token);
+        }
+        ("reject", Some(args)) => {
+            let id = identity::reject_pending(args.value_of("client").unwrap())?;
+            println!("Rejected client {}", id.id_token);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn invite(args: &clap::ArgMatches) -> Result<(), HermodError> {
+    if args.is_present("list") {
+        return hermod::invite::print_invites();
+    }
+
+    let hours = if args.is_present("expires") {
+        value_t!(args, "expires", i64).unwrap_or_else(|e| e.exit())
+    } else {
+        HERMOD_INVITE_VALIDITY
+    };
+    let label = args.value_of("label").unwrap_or_default();
+    let code = hermod::invite::create(chrono::Duration::hours(hours), label)?;
+    println!("{}", code);
+    eprintln!(
+        "The code can be used once within {} hours: hermod share-key --invite {} ...",
+        hours, code
+    );
+    Ok(())
+}
+
+fn pair(args: &clap::ArgMatches) -> Result<(), HermodError> {
+    let minutes = if args.is_present("expires") {
+        value_t!(args, "expires", i64).unwrap_or_else(|e| e.exit())
+    } else {
+        HERMOD_PAIR_VALIDITY
+    };
+    let label = args.value_of("label").unwrap_or_default();
+    let code = hermod::invite::create_pairing(chrono::Duration::minutes(minutes), label)?;
+    println!("{}", code);
+    eprintln!(
+        "The code can be used once within {} minutes: hermod share-key --code {} ...",
+        minutes, code
+    );
+    Ok(())
+}
+
 fn exec_request(args: &clap::ArgMatches, method: RequestMethod) {
     let host = match hermod::host::load_host(args.value_of("remote").unwrap()) {
         Ok(host) => host,
         Err(err) => {
-            eprintln!("Unknown remote host: {}", err);
+            eprintln!("Failed to load the remote host: {}", err);
+            return;
+        }
+    };
+    let settings = match ClientSettings::load(host.alias()) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err);
             return;
         }
     };
@@ -102,14 +399,43 @@ fn exec_request(args: &clap::ArgMatches, method: RequestMethod) {
         .values_of("source")
         .expect("Obligatory argument 'source' missing, aborting")
         .collect();
-    let destination = args
-        .value_of("destination")
-        .expect("Obligatory argument 'destination' missing, aborting");
 
-    let cfg_builder = ClientConfigBuilder::new(&host)
+    let include: Vec<&str> = args.values_of("include").unwrap_or_default().collect();
+    let exclude: Vec<&str> = args.values_of("exclude").unwrap_or_default().collect();
+    let filter = Filter::new()
+        .include(&include)
+        .exclude(&exclude)
+        .ignore_files(args.is_present("use-ignore-files"));
+
+    // Options given on the command line override the configuration file
+    let mut cfg_builder = ClientConfigBuilder::new(&host)
+        .settings(&settings)
         .source(&source)
-        .destination(destination)
-        .request(method);
+        .request(method)
+        .filter(filter);
+
+    // Only transfers and renames have a destination, the other requests operate on the source
+    if let Some(destination) = args.value_of("destination") {
+        cfg_builder = cfg_builder.destination(destination);
+    }
+    if args.is_present("port") {
+        cfg_builder = cfg_builder.port(value_t!(args, "port", u16).unwrap_or_else(|e| e.exit()));
+    }
+    if args.is_present("compress") {
+        cfg_builder = cfg_builder.compression(true);
+    }
+    if args.is_present("parallel") {
+        let parallel = value_t!(args, "parallel", usize).unwrap_or_else(|e| e.exit());
+        cfg_builder = cfg_builder.parallel(parallel);
+    }
+    if args.is_present("rate-limit") {
+        let rate_limit = value_t!(args, "rate-limit", u64).unwrap_or_else(|e| e.exit());
+        cfg_builder = cfg_builder.rate_limit(rate_limit);
+    }
+    if args.is_present("retries") {
+        let retries = value_t!(args, "retries", u32).unwrap_or_else(|e| e.exit());
+        cfg_builder = cfg_builder.retries(retries);
+    }
 
     let cfg = cfg_builder.build_config();
 
@@ -135,10 +461,20 @@ fn gen_key(args: &clap::ArgMatches) {
     let public_key = keys.public;
     let id_token = hermod::genkey::gen_idtoken();
 
+    let passphrase = match new_passphrase(args) {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
     let host = hermod::host::Host::with_alias(&alias)
         .set_id_token(&id_token)
         .set_public_key(&public_key)
-        .set_private_key(&private_key);
+        .set_private_key(private_key.as_bytes())
+        .set_passphrase(passphrase.as_deref().map(String::as_str))
+        .expect("Failed to encrypt the private key");
 
     println!("{}", host);
 
@@ -163,9 +499,254 @@ fn share_key(args: &clap::ArgMatches) {
         &name
     );
 
+    let passphrase = match new_passphrase(args) {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
     let host = args
         .value_of("host")
         .expect("No host address provided, aborting");
     let host = hermod::host::Host::with_alias(&name).set_hostname(host);
-    hermod::share_key::share_key(host);
+    let options = ShareKeyOptions {
+        passphrase: passphrase.as_deref().map(String::as_str),
+        fingerprint: args.value_of("expect-fingerprint"),
+        invite: args.value_of("invite"),
+        code: args.value_of("code"),
+    };
+    hermod::share_key::share_key(host, &options);
+}
+
+fn passphrase(args: &clap::ArgMatches) {
+    let alias = args.value_of("remote").unwrap();
+    let res = hermod::host::load_host(alias)
+        .and_then(|host| Ok((host, passphrase::read_new()?)))
+        .and_then(|(host, passphrase)| {
+            host.set_passphrase(passphrase.as_deref().map(String::as_str))
+        })
+        .and_then(|host| Ok(host.write_to_file()?));
+
+    match res {
+        Ok(()) => println!("Updated the passphrase of {}", alias),
+        Err(e) => eprintln!("Failed to update the passphrase: {}", e),
+    }
+}
+
+fn psk(args: &clap::ArgMatches) {
+    let alias = args.value_of("remote").unwrap();
+    let res = hermod::host::read_host(alias).and_then(|host| {
+        if args.is_present("remove") {
+            return Ok(host.set_psk(None).write_to_file()?);
+        }
+        let psk = match args.value_of("file") {
+            Some(file) => Zeroizing::new(std::fs::read_to_string(file)?),
+            None => Zeroizing::new(rpassword::read_password_from_tty(Some(
+                "Enter the pre-shared key: ",
+            ))?),
+        };
+        let psk = Zeroizing::new(base64::decode(psk.trim())?);
+        if psk.len() != PSK_LEN {
+            let msg = format!("expected a key of {} bytes", PSK_LEN);
+            return Err(HermodError::new(HermodErrorKind::Config(msg)));
+        }
+        Ok(host.set_psk(Some(&psk)).write_to_file()?)
+    });
+
+    match res {
+        Ok(()) if args.is_present("remove") => {
+            println!("No pre-shared key is used with {}", alias)
+        }
+        Ok(()) => println!("The pre-shared key is used when connecting to {}", alias),
+        Err(e) => {
+            eprintln!("Failed to set the pre-shared key: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn rotate_key(args: &clap::ArgMatches) {
+    let alias = args.value_of("remote").unwrap();
+    let res = hermod::host::load_host_with_passphrase(alias).and_then(|(host, passphrase)| {
+        let settings = ClientSettings::load(host.alias())?;
+        let keys = hermod::genkey::gen_keys()?;
+        let cfg_builder = ClientConfigBuilder::new(&host)
+            .settings(&settings)
+            .source(&[])
+            .request(RequestMethod::List);
+        let client = hermod::client::HermodClient::new(cfg_builder.build_config());
+        // The new key is protected by the same passphrase as the old one, and stored before the
+        // server replaces the key so that it survives a failure to update the host file
+        let staged = host
+            .clone()
+            .set_public_key(&keys.public)
+            .set_private_key(keys.private.as_bytes())
+            .set_passphrase(passphrase.as_deref().map(String::as_str))?;
+        let staged_path = staged.write_new_key()?;
+        let host = match async_std::task::block_on(client.rotate_key(&keys)) {
+            // Connecting may have updated the server key of the host
+            Ok(host) => staged.set_server_key(host.server_key()),
+            Err(e) => {
+                if let HermodErrorKind::Remote(_) = e.kind() {
+                    // The server refused the new key
+                    let _ = std::fs::remove_file(&staged_path);
+                } else {
+                    eprintln!(
+                        "The new key is kept in {}, use it as the host file if the server replaced the key",
+                        staged_path.display()
+                    );
+                }
+                return Err(e);
+            }
+        };
+        host.write_to_file()?;
+        let _ = std::fs::remove_file(&staged_path);
+        Ok(host)
+    });
+
+    match res {
+        Ok(host) => println!(
+            "Replaced the key used for {}, the new public key is {}",
+            alias,
+            base64::encode(&host.public_key)
+        ),
+        Err(e) => {
+            eprintln!("Failed to replace the key: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn ca(args: &clap::ArgMatches) {
+    let res = match args.subcommand() {
+        ("create", Some(args)) => create_ca(args),
+        ("sign", Some(args)) => sign_certificate(args),
+        _ => Ok(()),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn create_ca(args: &clap::ArgMatches) -> Result<(), HermodError> {
+    let (private_path, public_path) = certificate::ca_key_paths();
+    if private_path.exists() && !args.is_present("force") {
+        eprintln!("Found an existing certificate authority, to overwrite pass --force");
+        return Ok(());
+    }
+
+    let passphrase = new_passphrase(args)?;
+    let keys = hermod::genkey::gen_keys()?;
+    let private_key = match passphrase {
+        Some(ref passphrase) => passphrase::encrypt(keys.private.as_bytes(), passphrase)?,
+        None => base64::encode(&keys.private),
+    };
+    let private_key = Zeroizing::new(private_key);
+    if let Some(dir) = private_path.parent() {
+        hermod::permissions::create_private_dir(dir)?;
+    }
+    hermod::permissions::write_private_file(&private_path, private_key.as_bytes())?;
+    std::fs::write(&public_path, base64::encode(&keys.public))?;
+
+    println!(
+        "Created a certificate authority, servers trust it with: ca_key = \"{}\"",
+        public_path.display()
+    );
+    Ok(())
+}
+
+fn sign_certificate(args: &clap::ArgMatches) -> Result<(), HermodError> {
+    let days = if args.is_present("valid") {
+        value_t!(args, "valid", i64).unwrap_or_else(|e| e.exit())
+    } else {
+        HERMOD_CERTIFICATE_VALIDITY
+    };
+    let key = base64::decode(args.value_of("key").unwrap())?;
+    let principals: Vec<String> = args
+        .values_of("principal")
+        .unwrap_or_default()
+        .map(String::from)
+        .collect();
+
+    let (private_key, public_key) = certificate::read_ca_keys()?;
+    let now = chrono::Utc::now();
+    let certificate = Certificate::sign(
+        &private_key,
+        &public_key,
+        &key,
+        args.value_of("id").unwrap(),
+        &principals,
+        now,
+        now + chrono::Duration::days(days),
+    )?;
+    // Catches an invalid key or id token before the certificate is handed out
+    certificate.verify(&public_key, &[])?;
+    print!("{}", certificate.to_toml()?);
+    Ok(())
+}
+
+fn certificate(args: &clap::ArgMatches) {
+    let alias = args.value_of("remote").unwrap();
+    let res = std::fs::read_to_string(args.value_of("file").unwrap())
+        .map_err(HermodError::from)
+        .and_then(|content| Certificate::from_toml(&content))
+        .and_then(|certificate| {
+            let host = hermod::host::read_host(alias)?;
+            if certificate.id_token() != host.id_token()
+                || certificate.public_key()? != host.public_key()
+            {
+                return Err(HermodError::new(HermodErrorKind::Certificate(format!(
+                    "not issued for the key of {}",
+                    alias
+                ))));
+            }
+            if certificate.is_expired() {
+                return Err(HermodError::new(HermodErrorKind::Certificate(
+                    String::from("expired"),
+                )));
+            }
+            Ok(host.set_certificate(Some(certificate)).write_to_file()?)
+        });
+
+    match res {
+        Ok(()) => println!("The certificate is presented when connecting to {}", alias),
+        Err(e) => {
+            eprintln!("Failed to add the certificate: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Ask for the passphrase of a new private key if requested with --passphrase
+fn new_passphrase(args: &clap::ArgMatches) -> Result<Option<Zeroizing<String>>, HermodError> {
+    if args.is_present("passphrase") {
+        passphrase::read_new()
+    } else {
+        Ok(None)
+    }
+}
+
+fn shell(args: &clap::ArgMatches) {
+    let host = match hermod::host::load_host(args.value_of("remote").unwrap()) {
+        Ok(host) => host,
+        Err(err) => {
+            eprintln!("Failed to load the remote host: {}", err);
+            return;
+        }
+    };
+
+    let settings = match ClientSettings::load(host.alias()) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+
+    if let Err(e) = hermod::shell::run(&host, &settings) {
+        eprintln!("Connection to the server failed: {}", e);
+    }
 }
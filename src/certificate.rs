@@ -0,0 +1,250 @@
+use crate::consts::*;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::identity::Identity;
+use crate::passphrase;
+use crate::permissions;
+use crate::secret::SecretKey;
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use rand_core::OsRng;
+
+use serde::{Deserialize, Serialize};
+
+use xeddsa::xed25519::{PrivateKey, PublicKey};
+use xeddsa::{Sign, Verify};
+
+use zeroize::Zeroizing;
+
+/// Version of the certificate format, both on disk and when presented to the server
+const CERTIFICATE_VERSION: u32 = 1;
+
+/// A client key and id token signed by a certificate authority.
+/// A server that trusts the authority admits the client without it being in
+/// `authorized_clients`, as long as the certificate is valid and names an accepted principal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Certificate {
+    version: u32,
+    public_key: String,
+    id_token: String,
+    #[serde(default)]
+    principals: Vec<String>,
+    valid_after: String,
+    valid_before: String,
+    ca_key: String,
+    signature: String,
+}
+
+impl Certificate {
+    /// Certify that `public_key` belongs to the client `id_token` between `valid_after` and
+    /// `valid_before`. The signature is made with XEdDSA, the authority has an X25519 key like
+    /// the server.
+    pub fn sign(
+        ca_private_key: &SecretKey,
+        ca_key: &[u8],
+        public_key: &[u8],
+        id_token: &str,
+        principals: &[String],
+        valid_after: DateTime<Utc>,
+        valid_before: DateTime<Utc>,
+    ) -> Result<Self, HermodError> {
+        let private_key: Zeroizing<[u8; 32]> = Zeroizing::new(
+            ca_private_key
+                .as_bytes()
+                .try_into()
+                .map_err(|_| error("invalid length of the private key"))?,
+        );
+        let mut certificate = Certificate {
+            version: CERTIFICATE_VERSION,
+            public_key: base64::encode(public_key),
+            id_token: id_token.to_owned(),
+            principals: principals.to_vec(),
+            valid_after: valid_after.to_rfc3339_opts(SecondsFormat::Secs, true),
+            valid_before: valid_before.to_rfc3339_opts(SecondsFormat::Secs, true),
+            ca_key: base64::encode(ca_key),
+            signature: String::new(),
+        };
+        let signature: [u8; 64] = PrivateKey(*private_key).sign(&certificate.message(), OsRng);
+        certificate.signature = base64::encode(&signature[..]);
+        Ok(certificate)
+    }
+
+    /// Check that the certificate is signed by `ca_key`, is valid now and names one of
+    /// `principals`, any principal is accepted if `principals` is empty.
+    /// Returns the identity of the client.
+    pub fn verify(&self, ca_key: &[u8], principals: &[String]) -> Result<Identity, HermodError> {
+        if self.version != CERTIFICATE_VERSION {
+            return Err(error(&format!("unsupported version {}", self.version)));
+        }
+        let signer = base64::decode(&self.ca_key)?;
+        if signer != ca_key {
+            return Err(error("signed by an untrusted certificate authority"));
+        }
+        let signer: [u8; 32] = signer
+            .try_into()
+            .map_err(|_| error("invalid length of the certificate authority key"))?;
+        let signature: [u8; 64] = base64::decode(&self.signature)?
+            .try_into()
+            .map_err(|_| error("invalid length of the signature"))?;
+        PublicKey(signer)
+            .verify(&self.message(), &signature)
+            .map_err(|_| error("invalid signature"))?;
+
+        let now = Utc::now();
+        match (
+            parse_time(&self.valid_after),
+            parse_time(&self.valid_before),
+        ) {
+            (Some(after), Some(before)) if after <= now && now < before => (),
+            _ => return Err(error("not valid at this time")),
+        }
+        if !principals.is_empty() && !self.principals.iter().any(|p| principals.contains(p)) {
+            return Err(error("no accepted principal"));
+        }
+
+        let public_key = self.public_key()?;
+        let token = base64::decode(&self.id_token).unwrap_or_default();
+        if public_key.len() != 32 || token.len() != ID_TOKEN_LEN as usize {
+            return Err(error("invalid client key or id token"));
+        }
+        let label = self.principals.first().map(String::as_str).unwrap_or("");
+        let mut id = Identity::new(self.id_token.clone(), public_key).with_label(label);
+        id.certified = true;
+        Ok(id)
+    }
+
+    pub fn public_key(&self) -> Result<Vec<u8>, HermodError> {
+        Ok(base64::decode(&self.public_key)?)
+    }
+
+    pub fn id_token(&self) -> &str {
+        &self.id_token
+    }
+
+    pub fn principals(&self) -> &[String] {
+        &self.principals
+    }
+
+    /// The end of the validity window, an unreadable time is treated as already passed
+    pub fn valid_before(&self) -> Option<DateTime<Utc>> {
+        parse_time(&self.valid_before)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.valid_before()
+            .is_none_or(|before| before <= Utc::now())
+    }
+
+    pub fn to_toml(&self) -> Result<String, HermodError> {
+        toml::to_string(self).map_err(|e| error(&e.to_string()))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, HermodError> {
+        toml::from_str(content).map_err(|e| error(&e.to_string()))
+    }
+
+    /// The signed content, every field but the signature
+    fn message(&self) -> Vec<u8> {
+        let fields = (
+            self.version,
+            &self.public_key,
+            &self.id_token,
+            &self.principals,
+            &self.valid_after,
+            &self.valid_before,
+            &self.ca_key,
+        );
+        let mut message = b"hermod-certificate".to_vec();
+        message.extend(bincode::serialize(&fields).unwrap());
+        message
+    }
+}
+
+/// Paths of the private and public key of the certificate authority
+pub fn ca_key_paths() -> (PathBuf, PathBuf) {
+    let mut base_dir = PathBuf::new();
+    base_dir.push(dirs::home_dir().expect("Failed to get home directory path"));
+    base_dir.push(HERMOD_BASE_DIR);
+    (
+        base_dir.join(HERMOD_CA_PRIVATE_KEY_FILE),
+        base_dir.join(HERMOD_CA_PUBLIC_KEY_FILE),
+    )
+}
+
+/// Read the keys of the certificate authority, asking for the passphrase if the private key is
+/// protected by one
+pub fn read_ca_keys() -> Result<(SecretKey, Vec<u8>), HermodError> {
+    let (private_path, public_path) = ca_key_paths();
+    permissions::check_private_file(&private_path)?;
+    let content = Zeroizing::new(fs::read_to_string(&private_path)?);
+    let private_key = if passphrase::is_encrypted(&content) {
+        let passphrase =
+            passphrase::read("Enter passphrase for the certificate authority: ", None)?;
+        passphrase::decrypt(&content, &passphrase)?
+    } else {
+        SecretKey::new(&Zeroizing::new(base64::decode(content.trim())?))
+    };
+    let public_key = base64::decode(fs::read_to_string(&public_path)?.trim())?;
+    Ok((private_key, public_key))
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+fn error(msg: &str) -> HermodError {
+    HermodError::new(HermodErrorKind::Certificate(msg.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genkey;
+
+    #[test]
+    fn verify_signed_certificate() {
+        let ca = genkey::create_server_keys().unwrap();
+        let client = genkey::gen_keys().unwrap();
+        let token = genkey::gen_idtoken();
+        let principals = vec![String::from("backup")];
+        let now = Utc::now();
+        let sign = |after, before| {
+            Certificate::sign(
+                &ca.private,
+                &ca.public,
+                &client.public,
+                &token,
+                &principals,
+                after,
+                before,
+            )
+            .unwrap()
+        };
+
+        let certificate = sign(now, now + chrono::Duration::days(1));
+        let id = certificate.verify(&ca.public, &[]).unwrap();
+        assert_eq!(id.get_public_key(), &client.public[..]);
+        assert!(certificate.verify(&ca.public, &principals).is_ok());
+        assert!(certificate
+            .verify(&ca.public, &[String::from("web")])
+            .is_err());
+        assert!(certificate.verify(&client.public, &[]).is_err());
+
+        let mut forged = certificate.clone();
+        forged.principals.push(String::from("web"));
+        assert!(forged.verify(&ca.public, &[]).is_err());
+
+        let expired = sign(
+            now - chrono::Duration::days(2),
+            now - chrono::Duration::days(1),
+        );
+        assert!(expired.verify(&ca.public, &[]).is_err());
+    }
+}
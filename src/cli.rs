@@ -14,18 +14,217 @@ pub fn get_matches() -> clap::ArgMatches<'static> {
             .arg(Arg::with_name("no-daemon")
                 .long("no-daemon")
                 .help("Do not run the server in the background"))
+            .arg(Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Read the server configuration from FILE instead of ~/.hermod/server.toml"))
             .arg(Arg::with_name("ip")
                 .long("ip")
                 .takes_value(true)
-                .help("IP address to bind to")
-                .default_value("0.0.0.0"))
+                .multiple(true)
+                .number_of_values(1)
+                .help("IP address to bind to, may be repeated [default: 0.0.0.0]"))
+            .arg(Arg::with_name("port")
+                .long("port")
+                .short("p")
+                .takes_value(true)
+                .help("Port to listen on [default: 4444]"))
+            .arg(Arg::with_name("storage-root")
+                .long("storage-root")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Directory that relative paths in requests are resolved from [default: ~]"))
+            .arg(Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("File to write the server log to [default: ~/.hermod/server.log]"))
+            .arg(Arg::with_name("max-connections")
+                .long("max-connections")
+                .value_name("N")
+                .takes_value(true)
+                .help("Maximum number of simultaneous connections [default: 100]"))
+            .arg(Arg::with_name("pid-file")
+                .long("pid-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("File to write the daemons pid to [default: /tmp/hermod.pid]"))
+            .arg(Arg::with_name("passphrase-file")
+                .long("passphrase-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Read the passphrase of the private key from FILE instead of asking for it"))
+            .subcommand(SubCommand::with_name("check-config")
+                .about("Validate the server configuration and exit"))
             .subcommand(SubCommand::with_name("setup")
                 .about("Generate static keys for the server")
                 .arg(Arg::with_name("force")
                     .long("force")
-                    .help("Overwrite existing keys if found")))
+                    .help("Overwrite existing keys if found"))
+                .arg(Arg::with_name("passphrase")
+                    .long("passphrase")
+                    .help("Protect the private key with a passphrase")))
+            .subcommand(SubCommand::with_name("passphrase")
+                .about("Add, change or remove the passphrase of the servers private key"))
             .subcommand(SubCommand::with_name("list")
-                .about("List all authorized client")))
+                .about("List all authorized client"))
+            .subcommand(SubCommand::with_name("invite")
+                .about("Create a single use invitation code that allows a client to share its key")
+                .arg(Arg::with_name("expires")
+                    .long("expires")
+                    .value_name("HOURS")
+                    .takes_value(true)
+                    .help("Number of hours the code is valid for [default: 24]"))
+                .arg(Arg::with_name("label")
+                    .long("label")
+                    .value_name("LABEL")
+                    .takes_value(true)
+                    .help("Label to give the client that uses the code"))
+                .arg(Arg::with_name("list")
+                    .long("list")
+                    .conflicts_with_all(&["expires", "label"])
+                    .help("List the invitations that have not been used yet")))
+            .subcommand(SubCommand::with_name("pair")
+                .about("Create a short single use code for pairing a client, the code authenticates both the client and the server")
+                .arg(Arg::with_name("expires")
+                    .long("expires")
+                    .value_name("MINUTES")
+                    .takes_value(true)
+                    .help("Number of minutes the code is valid for [default: 10]"))
+                .arg(Arg::with_name("label")
+                    .long("label")
+                    .value_name("LABEL")
+                    .takes_value(true)
+                    .help("Label to give the client that uses the code")))
+            .subcommand(SubCommand::with_name("pending")
+                .about("Manage the clients awaiting approval")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list")
+                    .about("List the clients awaiting approval"))
+                .subcommand(SubCommand::with_name("approve")
+                    .about("Authorize a pending client")
+                    .arg(Arg::with_name("client")
+                        .value_name("CLIENT")
+                        .required(true)
+                        .help("The id token or label of the client"))
+                    .arg(Arg::with_name("label")
+                        .long("label")
+                        .value_name("LABEL")
+                        .takes_value(true)
+                        .help("A name to refer to the client by")))
+                .subcommand(SubCommand::with_name("reject")
+                    .about("Remove a pending client without authorizing it")
+                    .arg(Arg::with_name("client")
+                        .value_name("CLIENT")
+                        .required(true)
+                        .help("The id token or label of the client"))))
+            .subcommand(SubCommand::with_name("rotate-key")
+                .about("Replace the static key of the server, clients learn about the new key when they connect during the grace period")
+                .arg(Arg::with_name("grace")
+                    .long("grace")
+                    .value_name("DAYS")
+                    .takes_value(true)
+                    .help("Number of days the previous key is still accepted [default: 30]"))
+                .arg(Arg::with_name("force")
+                    .long("force")
+                    .help("Rotate the key even if the grace period of the previous rotation has not ended")))
+            .subcommand(SubCommand::with_name("fingerprint")
+                .about("Print the fingerprint of the server key, for clients to verify when sharing their key"))
+            .subcommand(SubCommand::with_name("clients")
+                .about("Manage the authorized clients")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("add")
+                    .about("Authorize a new client")
+                    .arg(Arg::with_name("id")
+                        .long("id")
+                        .value_name("TOKEN")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The id token of the client"))
+                    .arg(Arg::with_name("key")
+                        .long("key")
+                        .value_name("PUBLIC_KEY")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The public key of the client, base64 encoded"))
+                    .arg(Arg::with_name("label")
+                        .long("label")
+                        .value_name("LABEL")
+                        .takes_value(true)
+                        .help("A name to refer to the client by"))
+                    .arg(Arg::with_name("not-before")
+                        .long("not-before")
+                        .value_name("TIME")
+                        .takes_value(true)
+                        .help("The client may not connect before TIME, a date (YYYY-MM-DD) or RFC 3339 time"))
+                    .arg(Arg::with_name("not-after")
+                        .long("not-after")
+                        .value_name("TIME")
+                        .takes_value(true)
+                        .help("The access of the client expires at TIME, a date (YYYY-MM-DD) or RFC 3339 time"))
+                    .arg(Arg::with_name("hours")
+                        .long("hours")
+                        .value_name("HH:MM-HH:MM")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Hours of the day the client may connect in, in the local time of the server")))
+                .subcommand(SubCommand::with_name("limit")
+                    .about("Limit when a client may connect, replacing any previous limits")
+                    .arg(Arg::with_name("client")
+                        .value_name("CLIENT")
+                        .required(true)
+                        .help("The id token or label of the client"))
+                    .arg(Arg::with_name("not-before")
+                        .long("not-before")
+                        .value_name("TIME")
+                        .takes_value(true)
+                        .help("The client may not connect before TIME, a date (YYYY-MM-DD) or RFC 3339 time"))
+                    .arg(Arg::with_name("not-after")
+                        .long("not-after")
+                        .value_name("TIME")
+                        .takes_value(true)
+                        .help("The access of the client expires at TIME, a date (YYYY-MM-DD) or RFC 3339 time"))
+                    .arg(Arg::with_name("hours")
+                        .long("hours")
+                        .value_name("HH:MM-HH:MM")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Hours of the day the client may connect in, in the local time of the server")))
+                .subcommand(SubCommand::with_name("remove")
+                    .about("Revoke the access of a client")
+                    .arg(Arg::with_name("client")
+                        .value_name("CLIENT")
+                        .required(true)
+                        .help("The id token or label of the client")))
+                .subcommand(SubCommand::with_name("rename")
+                    .about("Change the label of a client")
+                    .arg(Arg::with_name("client")
+                        .value_name("CLIENT")
+                        .required(true)
+                        .help("The id token or label of the client"))
+                    .arg(Arg::with_name("label")
+                        .value_name("LABEL")
+                        .required(true)
+                        .help("The new label")))
+                .subcommand(SubCommand::with_name("show")
+                    .about("Display the details of a client")
+                    .arg(Arg::with_name("client")
+                        .value_name("CLIENT")
+                        .required(true)
+                        .help("The id token or label of the client")))
+                .subcommand(SubCommand::with_name("psk")
+                    .about("Generate a pre-shared key the client must mix into the handshake, and print it for 'hermod psk' on the client")
+                    .arg(Arg::with_name("client")
+                        .value_name("CLIENT")
+                        .required(true)
+                        .help("The id token or label of the client"))
+                    .arg(Arg::with_name("remove")
+                        .long("remove")
+                        .help("Remove the pre-shared key of the client instead")))))
         .subcommand(SubCommand::with_name("gen-key")
             .about("Generate static keys for the client and a new client-token")
             .arg(Arg::with_name("force")
@@ -35,7 +234,10 @@ pub fn get_matches() -> clap::ArgMatches<'static> {
                 .required(true)
                 .takes_value(true)
                 .long("alias")
-                .help("Alias to use for the remote server")))
+                .help("Alias to use for the remote server"))
+            .arg(Arg::with_name("passphrase")
+                .long("passphrase")
+                .help("Protect the private key with a passphrase")))
         .subcommand(SubCommand::with_name("share-key")
             .about("Generate and immediately share keys and id with the specified host")
             .arg(Arg::with_name("force")
@@ -54,7 +256,103 @@ pub fn get_matches() -> clap::ArgMatches<'static> {
                 .value_name("NAME")
                 .takes_value(true)
                 .required(true)
-                .help("The remote hostname or ip address for the server to share a public keys with. Generates new client keys for the server if they do not exist")))
+                .help("The remote hostname or ip address for the server to share a public keys with. Generates new client keys for the server if they do not exist"))
+            .arg(Arg::with_name("passphrase")
+                .long("passphrase")
+                .help("Protect the private key with a passphrase"))
+            .arg(Arg::with_name("expect-fingerprint")
+                .long("expect-fingerprint")
+                .value_name("FINGERPRINT")
+                .takes_value(true)
+                .help("Fingerprint the server key must have, as printed by 'hermod server fingerprint'. Asks for confirmation if not given"))
+            .arg(Arg::with_name("invite")
+                .long("invite")
+                .short("i")
+                .value_name("CODE")
+                .takes_value(true)
+                .help("Invitation code from 'hermod server invite'"))
+            .arg(Arg::with_name("code")
+                .long("code")
+                .short("c")
+                .value_name("CODE")
+                .takes_value(true)
+                .conflicts_with("invite")
+                .help("Pairing code from 'hermod server pair'")))
+        .subcommand(SubCommand::with_name("passphrase")
+            .about("Add, change or remove the passphrase of the private key used for a remote server")
+            .arg(Arg::with_name("remote")
+                .value_name("REMOTE")
+                .required(true)
+                .help("The alias for the remote server")))
+        .subcommand(SubCommand::with_name("ca")
+            .about("Manage a certificate authority that signs client keys")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("create")
+                .about("Generate the keys of the certificate authority")
+                .arg(Arg::with_name("force")
+                    .long("force")
+                    .help("Overwrite existing keys if found"))
+                .arg(Arg::with_name("passphrase")
+                    .long("passphrase")
+                    .help("Protect the private key with a passphrase")))
+            .subcommand(SubCommand::with_name("sign")
+                .about("Sign the key of a client, the certificate is written to stdout")
+                .arg(Arg::with_name("id")
+                    .long("id")
+                    .value_name("TOKEN")
+                    .takes_value(true)
+                    .required(true)
+                    .help("The id token of the client"))
+                .arg(Arg::with_name("key")
+                    .long("key")
+                    .value_name("PUBLIC_KEY")
+                    .takes_value(true)
+                    .required(true)
+                    .help("The public key of the client, base64 encoded"))
+                .arg(Arg::with_name("principal")
+                    .long("principal")
+                    .short("n")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("A principal the certificate is valid for, matched against ca_principals of the server"))
+                .arg(Arg::with_name("valid")
+                    .long("valid")
+                    .value_name("DAYS")
+                    .takes_value(true)
+                    .help("Number of days the certificate is valid for, defaults to 90"))))
+        .subcommand(SubCommand::with_name("certificate")
+            .about("Present a certificate signed by a certificate authority when connecting to a remote server")
+            .arg(Arg::with_name("remote")
+                .value_name("REMOTE")
+                .required(true)
+                .help("The alias for the remote server"))
+            .arg(Arg::with_name("file")
+                .value_name("FILE")
+                .required(true)
+                .help("The certificate from 'hermod ca sign'")))
+        .subcommand(SubCommand::with_name("psk")
+            .about("Mix a pre-shared key from 'hermod server clients psk' into the handshake with a remote server")
+            .arg(Arg::with_name("remote")
+                .value_name("REMOTE")
+                .required(true)
+                .help("The alias for the remote server"))
+            .arg(Arg::with_name("file")
+                .long("file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Read the key from FILE instead of asking for it"))
+            .arg(Arg::with_name("remove")
+                .long("remove")
+                .conflicts_with("file")
+                .help("Stop using a pre-shared key")))
+        .subcommand(SubCommand::with_name("rotate-key")
+            .about("Replace the key used for a remote server, the server is sent the new public key over a session authenticated with the current key")
+            .arg(Arg::with_name("remote")
+                .value_name("REMOTE")
+                .required(true)
+                .help("The alias for the remote server")))
         .subcommand(SubCommand::with_name("upload")
             .about("Upload a file or files to the remote server")
             .arg(Arg::with_name("remote")
@@ -69,8 +367,7 @@ pub fn get_matches() -> clap::ArgMatches<'static> {
                 .short("d")
                 .value_name("DESTINATION")
                 .takes_value(true)
-                .required(true)
-                .help("Destination folder for the transmitted files"))
+                .help("Destination folder for the transmitted files [default: the configured remote_dir]"))
             .arg(Arg::with_name("source")
                 .long("source")
                 .short("s")
@@ -78,7 +375,49 @@ pub fn get_matches() -> clap::ArgMatches<'static> {
                 .takes_value(true)
                 .required(true)
                 .multiple(true)
-                .help("The source file or files to send to the server")))
+                .help("The source file or files to send to the server"))
+            .arg(Arg::with_name("include")
+                .long("include")
+                .value_name("PATTERN")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("When transferring directories, only include files matching the pattern, may be repeated"))
+            .arg(Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("PATTERN")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("When transferring directories, skip files and directories matching the pattern, may be repeated"))
+            .arg(Arg::with_name("use-ignore-files")
+                .long("use-ignore-files")
+                .help("Skip files listed in .gitignore and .hermodignore files"))
+            .arg(Arg::with_name("port")
+                .long("port")
+                .short("p")
+                .takes_value(true)
+                .help("Connect to this port instead of the one stored for the remote"))
+            .arg(Arg::with_name("compress")
+                .long("compress")
+                .short("z")
+                .help("Compress the file content during the transfer"))
+            .arg(Arg::with_name("parallel")
+                .long("parallel")
+                .short("j")
+                .value_name("N")
+                .takes_value(true)
+                .help("Transfer files over N simultaneous connections"))
+            .arg(Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Limit the transfer rate to BYTES per second"))
+            .arg(Arg::with_name("retries")
+                .long("retries")
+                .value_name("N")
+                .takes_value(true)
+                .help("Reconnect and retry up to N times if the connection is lost")))
         .subcommand(SubCommand::with_name("download")
             .about("Download a file or files from the remote server")
             .arg(Arg::with_name("remote")
@@ -93,13 +432,130 @@ pub fn get_matches() -> clap::ArgMatches<'static> {
                 .short("d")
                 .value_name("DESTINATION")
                 .takes_value(true)
+                .help("Destination folder for the transmitted files [default: .]"))
+            .arg(Arg::with_name("source")
+                .long("source")
+                .short("s")
+                .value_name("SOURCE")
+                .takes_value(true)
+                .required(true)
+                .multiple(true)
+                .help("The source file or files to downlaod from the server, patterns such as 'logs/*.gz' are expanded by the server"))
+            .arg(Arg::with_name("include")
+                .long("include")
+                .value_name("PATTERN")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("When transferring directories, only include files matching the pattern, may be repeated"))
+            .arg(Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("PATTERN")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("When transferring directories, skip files and directories matching the pattern, may be repeated"))
+            .arg(Arg::with_name("use-ignore-files")
+                .long("use-ignore-files")
+                .help("Skip files listed in .gitignore and .hermodignore files"))
+            .arg(Arg::with_name("port")
+                .long("port")
+                .short("p")
+                .takes_value(true)
+                .help("Connect to this port instead of the one stored for the remote"))
+            .arg(Arg::with_name("compress")
+                .long("compress")
+                .short("z")
+                .help("Compress the file content during the transfer"))
+            .arg(Arg::with_name("parallel")
+                .long("parallel")
+                .short("j")
+                .value_name("N")
+                .takes_value(true)
+                .help("Transfer files over N simultaneous connections"))
+            .arg(Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Limit the transfer rate to BYTES per second"))
+            .arg(Arg::with_name("retries")
+                .long("retries")
+                .value_name("N")
+                .takes_value(true)
+                .help("Reconnect and retry up to N times if the connection is lost")))
+        .subcommand(SubCommand::with_name("mkdir")
+            .about("Create a directory, and any missing parents, on the remote server")
+            .arg(Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .takes_value(true)
+                .required(true)
+                .help("The alias for the remote server"))
+            .arg(Arg::with_name("source")
+                .value_name("PATH")
+                .required(true)
+                .multiple(true)
+                .help("The directory or directories to create")))
+        .subcommand(SubCommand::with_name("rm")
+            .about("Remove a file or directory from the remote server")
+            .arg(Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .takes_value(true)
                 .required(true)
-                .help("Destination folder for the transmitted files"))
+                .help("The alias for the remote server"))
+            .arg(Arg::with_name("recursive")
+                .long("recursive")
+                .short("R")
+                .help("Remove directories and their content recursively"))
+            .arg(Arg::with_name("source")
+                .value_name("PATH")
+                .required(true)
+                .multiple(true)
+                .help("The file or files to remove")))
+        .subcommand(SubCommand::with_name("mv")
+            .about("Rename or move a file or directory on the remote server")
+            .arg(Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .takes_value(true)
+                .required(true)
+                .help("The alias for the remote server"))
             .arg(Arg::with_name("source")
                 .long("source")
                 .short("s")
                 .value_name("SOURCE")
                 .takes_value(true)
                 .required(true)
-                .help("The source file or files to downlaod from the server"))).get_matches()
+                .help("The file or directory to move"))
+            .arg(Arg::with_name("destination")
+                .long("destination")
+                .short("d")
+                .value_name("DESTINATION")
+                .takes_value(true)
+                .required(true)
+                .help("The new path, or an existing directory to move the source into")))
+        .subcommand(SubCommand::with_name("stat")
+            .about("Display information about a file or directory on the remote server")
+            .arg(Arg::with_name("remote")
+                .long("remote")
+                .short("r")
+                .value_name("REMOTE")
+                .takes_value(true)
+                .required(true)
+                .help("The alias for the remote server"))
+            .arg(Arg::with_name("source")
+                .value_name("PATH")
+                .required(true)
+                .multiple(true)
+                .help("The file or files to display information about")))
+        .subcommand(SubCommand::with_name("shell")
+            .about("Open an interactive session with the remote server")
+            .arg(Arg::with_name("remote")
+                .value_name("REMOTE")
+                .required(true)
+                .help("The alias for the remote server"))).get_matches()
 }
@@ -1,9 +1,16 @@
 use crate::config::ClientConfig;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::genkey::KeyPair;
+use crate::host::Host;
+use crate::message::{Message, MessageType};
 use crate::peer::Endpoint;
 use crate::peer::Peer;
-use crate::request::Request;
+use crate::request::{self, Request};
 
 use async_std::net::TcpStream;
+use async_std::task;
+
+use futures::future;
 
 pub struct HermodClient<'hc> {
     config: ClientConfig<'hc>,
@@ -14,44 +21,148 @@ impl<'hc> HermodClient<'hc> {
         HermodClient { config }
     }
 
+    /// Connect to the server and conduct the noise handshake
+    pub async fn connect(&self) -> Result<Endpoint, HermodError> {
+        let mut stream = TcpStream::connect(self.config.address()).await?;
+        let peer = Peer::new_server_peer(self.config.host());
+        Endpoint::client(&mut stream, peer, &self.config).await
+    }
+
+    /// Replace the key of the client by `keys`, over a session authenticated with the current
+    /// key. Returns the host with the new key, to be written to the host file.
+    pub async fn rotate_key(&self, keys: &KeyPair) -> Result<Host, HermodError> {
+        let mut endpoint = self.connect().await?;
+        let msg = Message::new(MessageType::RotateKey, &keys.public);
+        endpoint.send(&msg).await?;
+        let msg = endpoint.recv().await?;
+        match msg.get_type() {
+            MessageType::Okay => (),
+            MessageType::Error => return Err(request::remote_error(&msg)),
+            _ => return Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+        }
+
+        // The server has stored the new key, the session is no longer needed
+        let _ = endpoint.close().await;
+        // Connecting may have updated the server key of the host
+        let host = match endpoint.get_peer() {
+            Peer::Host(host) => host.clone(),
+            Peer::Identity(_) => unreachable!("The client connects to a host"),
+        };
+        Ok(host
+            .set_public_key(&keys.public)
+            .set_private_key(keys.private.as_bytes()))
+    }
+
     pub fn execute(&self) {
         async_std::task::block_on(async {
-            let mut stream = match TcpStream::connect(self.config.get_hostname()).await {
-                Ok(stream) => stream,
+            let requests = match Request::from(&self.config) {
+                Ok(requests) => requests,
                 Err(e) => {
-                    eprintln!("Failed to connect to server: {}", e);
+                    eprintln!("{}", e);
                     return;
                 }
             };
-            let peer = match Peer::new_server_peer(self.config.get_alias()).await {
-                Ok(peer) => peer,
-                Err(_) => {
-                    eprintln!(
-                        "Cound not find a server with that alias ({}). Aborting...",
-                        self.config.get_alias()
-                    );
+            // Patterns are expanded before anything is transferred, so that a pattern that fails
+            // does not leave the transfer half done
+            let requests = match self.expand(requests).await {
+                Ok(requests) => requests,
+                Err(e) => {
+                    eprintln!("Failed to expand the sources: {}", e);
                     return;
                 }
             };
-            // Conduct noise handshake
-            // TODO: Better error message
-            let mut endpoint = Endpoint::client(&mut stream, peer, &self.config)
-                .await
-                .unwrap();
-            match Request::from(&self.config) {
-                Ok(requests) => {
-                    // Execute the requests
-                    match Request::exec_all(&mut endpoint, &requests).await {
-                        Ok(_) => (),
-                        Err(e) => {
-                            // Close connection on first error
-                            // Try to send the other requests on error?
-                            eprintln!("Failed to execute the request: {}", e);
-                        }
+
+            // Spread the requests over the connections, the rate limit is shared between them
+            let connections = self.config.parallel.min(requests.len()).max(1);
+            let rate_limit = self.config.rate_limit.map(|rate| rate / connections as u64);
+
+            let mut queues = vec![Vec::new(); connections];
+            for (i, request) in requests.iter().enumerate() {
+                queues[i % connections].push(request);
+            }
+
+            future::join_all(
+                queues
+                    .iter()
+                    .map(|queue| self.exec_queue(queue, rate_limit)),
+            )
+            .await;
+        });
+    }
+
+    /// Execute the requests in order over a single connection, reconnecting if it is lost
+    async fn exec_queue(&self, requests: &[&Request], rate_limit: Option<u64>) {
+        // TODO: Better error message
+        let mut endpoint = match self.reconnect(rate_limit).await {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                eprintln!("Failed to connect to server: {}", e);
+                return;
+            }
+        };
+
+        for request in requests {
+            let mut attempt = 0;
+            loop {
+                match request.exec_literal(&mut endpoint).await {
+                    Ok(()) => break,
+                    Err(e) if e.is_connection_error() && attempt < self.config.retries => {
+                        attempt += 1;
+                        eprintln!(
+                            "Lost the connection to the server: {}, retrying ({}/{})",
+                            e, attempt, self.config.retries
+                        );
+                        task::sleep(self.config.retry_delay).await;
+                        endpoint = match self.reconnect(rate_limit).await {
+                            Ok(endpoint) => endpoint,
+                            Err(e) => {
+                                eprintln!("Failed to connect to server: {}", e);
+                                return;
+                            }
+                        };
+                    }
+                    Err(e) => {
+                        // Close connection on first error
+                        eprintln!("Failed to execute the request: {}", e);
+                        return;
                     }
                 }
-                Err(e) => eprintln!("{}", e),
-            };
-        });
+            }
+        }
+    }
+
+    /// Replace the downloads of patterns with downloads of the files they match on the server
+    async fn expand(&self, requests: Vec<Request>) -> Result<Vec<Request>, HermodError> {
+        if !requests.iter().any(Request::is_pattern) {
+            return Ok(requests);
+        }
+        let mut endpoint = self.reconnect(None).await?;
+        let mut expanded = Vec::new();
+        for request in &requests {
+            expanded.append(&mut request.expand(&mut endpoint).await?);
+        }
+        Ok(expanded)
+    }
+
+    /// Connect to the server, retrying according to the retry policy
+    async fn reconnect(&self, rate_limit: Option<u64>) -> Result<Endpoint, HermodError> {
+        let mut attempt = 0;
+        loop {
+            match self.connect().await {
+                Ok(mut endpoint) => {
+                    endpoint.set_rate_limit(rate_limit);
+                    return Ok(endpoint);
+                }
+                Err(e) if e.is_connection_error() && attempt < self.config.retries => {
+                    attempt += 1;
+                    eprintln!(
+                        "Failed to connect to server: {}, retrying ({}/{})",
+                        e, attempt, self.config.retries
+                    );
+                    task::sleep(self.config.retry_delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 }
@@ -1,27 +1,130 @@
 use crate::consts::*;
-use crate::host::Host;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::filter::Filter;
+use crate::host::{self, Host};
+use crate::passphrase;
+use crate::permissions;
 use crate::request::RequestMethod;
+use crate::rotation::{self, KeyRotation};
+use crate::secret::SecretKey;
+use crate::suite::Suite;
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
 
+use log::warn;
+
+use serde::Deserialize;
+
+use zeroize::Zeroizing;
+
 lazy_static! {
+    pub static ref SERVER_SETTINGS: RwLock<ServerSettings> = RwLock::new(ServerSettings::default());
     pub static ref SERVER_CONFIG: ServerConfig = ServerConfig::new();
 }
 
 pub trait Config<'cfg>: Send + Sync {
     fn get_private_key(&self) -> &[u8];
     fn get_public_key(&self) -> &[u8];
+
+    /// The key replaced by the last key rotation, while it is still accepted
+    fn get_previous_private_key(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The cipher suites to negotiate for a session, in order of preference
+    fn suites(&self) -> Vec<Suite> {
+        vec![Suite::default()]
+    }
+
+    /// Whether sessions use the hybrid post-quantum handshake, a server requires it of clients
+    fn post_quantum(&self) -> bool {
+        false
+    }
+}
+
+/// Settings for running the server, read from the server configuration file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub storage_root: PathBuf,
+    pub private_key: PathBuf,
+    pub public_key: PathBuf,
+    pub log_file: PathBuf,
+    pub log_level: String,
+    pub max_connections: usize,
+    pub pid_file: PathBuf,
+    pub passphrase_file: Option<PathBuf>,
+    pub enrollment: Enrollment,
+    /// Public key of a certificate authority, clients with a certificate it signed are admitted
+    /// without being in authorized_clients
+    pub ca_key: Option<PathBuf>,
+    /// Principals of which a certificate must name at least one, any principal if empty
+    pub ca_principals: Vec<String>,
+    /// Cipher suites accepted for sessions, the first one the client offers is used
+    pub suites: Vec<Suite>,
+    /// Refuse clients that do not use the hybrid post-quantum handshake
+    pub require_post_quantum: bool,
+    /// Handshakes each address may start per minute, any number if 0
+    pub handshake_rate: u32,
+    /// Handshakes in progress above which clients must present a cookie for their address
+    pub handshake_load: usize,
+}
+
+/// Which clients may enroll by sharing their key with the server
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Enrollment {
+    /// Only clients presenting an invitation code from `hermod server invite`
+    Invite,
+    /// Any client that can reach the server
+    Open,
+    /// Clients presenting an invitation code, other clients are queued until they are approved
+    /// with `hermod server pending approve`
+    Approval,
+}
+
+/// Client settings read from the client configuration file.
+/// Settings left out are taken from the defaults section, or from the command line.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    pub remote_dir: Option<String>,
+    pub port: Option<u16>,
+    pub compression: Option<bool>,
+    pub parallel: Option<usize>,
+    pub rate_limit: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_delay: Option<u64>,
+    pub suites: Option<Vec<Suite>>,
+    pub post_quantum: Option<bool>,
+}
+
+/// Layout of ~/.hermod/config, a defaults section followed by a section per host alias
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClientSettingsFile {
+    defaults: ClientSettings,
+    host: HashMap<String, ClientSettings>,
 }
 
 #[derive(Clone)]
 pub struct ServerConfig {
     public_key: Vec<u8>,
-    private_key: Vec<u8>,
+    private_key: SecretKey,
+    previous_private_key: Option<SecretKey>,
+    key_rotation: Option<KeyRotation>,
+    ca_key: Option<Vec<u8>>,
 }
 
 pub struct ClientConfigBuilder<'builder> {
@@ -29,6 +132,16 @@ pub struct ClientConfigBuilder<'builder> {
     pub source: Option<&'builder [&'builder str]>,
     pub destination: Option<&'builder str>,
     pub request: Option<RequestMethod>,
+    pub filter: Filter,
+    pub remote_dir: Option<&'builder str>,
+    pub port: Option<u16>,
+    pub compression: bool,
+    pub parallel: usize,
+    pub rate_limit: Option<u64>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub suites: Vec<Suite>,
+    pub post_quantum: bool,
 }
 
 pub struct ClientConfig<'builder> {
@@ -36,16 +149,41 @@ pub struct ClientConfig<'builder> {
     pub source: Vec<&'builder str>,
     pub destination: &'builder str,
     pub request: RequestMethod,
+    pub filter: Filter,
+    pub remote_dir: Option<&'builder str>,
+    pub port: Option<u16>,
+    pub compression: bool,
+    pub parallel: usize,
+    pub rate_limit: Option<u64>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub suites: Vec<Suite>,
+    pub post_quantum: bool,
 }
 
 impl Config<'_> for ServerConfig {
     fn get_private_key(&self) -> &[u8] {
-        &self.private_key
+        self.private_key.as_bytes()
     }
 
     fn get_public_key(&self) -> &[u8] {
         &self.public_key
     }
+
+    fn get_previous_private_key(&self) -> Option<&[u8]> {
+        self.key_rotation()
+            .filter(|rotation| !rotation.is_expired())
+            .and(self.previous_private_key.as_ref())
+            .map(SecretKey::as_bytes)
+    }
+
+    fn suites(&self) -> Vec<Suite> {
+        SERVER_SETTINGS.read().unwrap().suites.clone()
+    }
+
+    fn post_quantum(&self) -> bool {
+        SERVER_SETTINGS.read().unwrap().require_post_quantum
+    }
 }
 
 impl<'builder> Config<'builder> for ClientConfig<'builder> {
@@ -56,6 +194,14 @@ impl<'builder> Config<'builder> for ClientConfig<'builder> {
     fn get_public_key(&self) -> &[u8] {
         &self.host.public_key()
     }
+
+    fn suites(&self) -> Vec<Suite> {
+        self.suites.clone()
+    }
+
+    fn post_quantum(&self) -> bool {
+        self.post_quantum
+    }
 }
 
 impl<'builder> ClientConfigBuilder<'builder> {
@@ -65,9 +211,38 @@ impl<'builder> ClientConfigBuilder<'builder> {
             source: None,
             destination: None,
             request: None,
+            filter: Filter::default(),
+            remote_dir: None,
+            port: None,
+            compression: false,
+            parallel: 1,
+            rate_limit: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(HERMOD_RETRY_DELAY),
+            suites: vec![Suite::default()],
+            post_quantum: false,
         }
     }
 
+    /// Apply the settings from the client configuration file, call before any other setter
+    /// so that options given on the command line take precedence
+    pub fn settings(mut self, settings: &'builder ClientSettings) -> Self {
+        self.remote_dir = settings.remote_dir.as_deref().or(self.remote_dir);
+        self.port = settings.port.or(self.port);
+        self.compression = settings.compression.unwrap_or(self.compression);
+        self.parallel = settings.parallel.unwrap_or(self.parallel);
+        self.rate_limit = settings.rate_limit.or(self.rate_limit);
+        self.retries = settings.retries.unwrap_or(self.retries);
+        if let Some(delay) = settings.retry_delay {
+            self.retry_delay = Duration::from_secs(delay);
+        }
+        if let Some(ref suites) = settings.suites {
+            self.suites = suites.clone();
+        }
+        self.post_quantum = settings.post_quantum.unwrap_or(self.post_quantum);
+        self
+    }
+
     pub fn source(mut self, source: &'builder [&'builder str]) -> Self {
         self.source = Some(source);
         self
@@ -83,43 +258,341 @@ impl<'builder> ClientConfigBuilder<'builder> {
         self
     }
 
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Spread the transfers over `connections` simultaneous connections
+    pub fn parallel(mut self, connections: usize) -> Self {
+        self.parallel = connections;
+        self
+    }
+
+    /// Limit the combined throughput of all connections to `bytes` per second
+    pub fn rate_limit(mut self, bytes: u64) -> Self {
+        self.rate_limit = Some(bytes);
+        self
+    }
+
+    /// Reconnect and retry a request up to `retries` times when the connection is lost
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
     pub fn build_config(&self) -> ClientConfig {
         let source = self.source.expect("No source file specified");
-        let destination = self.destination.expect("No destination specified");
+        let destination = self.destination.unwrap_or_default();
         let request = self.request.expect("No request method specified");
 
-        ClientConfig::new(self.host, source, destination, request)
+        let mut config = ClientConfig::new(self.host, source, destination, request);
+        config.filter = self.filter.clone();
+        config.remote_dir = self.remote_dir;
+        config.port = self.port;
+        config.compression = self.compression;
+        config.parallel = self.parallel.max(1);
+        config.rate_limit = self.rate_limit.filter(|rate| *rate > 0);
+        config.retries = self.retries;
+        config.retry_delay = self.retry_delay;
+        config.suites = self.suites.clone();
+        config.post_quantum = self.post_quantum;
+        config
+    }
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        let base_dir = hermod_dir();
+        ServerSettings {
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: HERMOD_PORT,
+            storage_root: dirs::home_dir().expect("Failed to get home directory"),
+            private_key: base_dir.join(SERVER_PRIVATE_KEY_FILE),
+            public_key: base_dir.join(SERVER_PUBLIC_KEY_FILE),
+            log_file: base_dir.join(HERMOD_LOG_FILE),
+            log_level: String::from("info"),
+            max_connections: 100,
+            pid_file: PathBuf::from(HERMOD_PID_FILE),
+            passphrase_file: None,
+            enrollment: Enrollment::Invite,
+            ca_key: None,
+            ca_principals: Vec::new(),
+            suites: vec![Suite::default()],
+            require_post_quantum: false,
+            handshake_rate: 60,
+            handshake_load: 16,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Read the settings from `path`, or from the default location if no path is given.
+    /// A missing default configuration file is not an error, the defaults are used instead.
+    pub fn load(path: Option<&Path>) -> Result<Self, HermodError> {
+        let default_path = hermod_dir().join(HERMOD_SERVER_CONFIG_FILE);
+        let path = match path {
+            Some(path) => path,
+            None if !default_path.exists() => return Ok(ServerSettings::default()),
+            None => &default_path,
+        };
+
+        let content = std::fs::read_to_string(path)?;
+        let mut settings: ServerSettings = toml::from_str(&content).map_err(|e| {
+            HermodError::new(HermodErrorKind::Config(format!(
+                "{}: {}",
+                path.display(),
+                e
+            )))
+        })?;
+
+        settings.storage_root = expand_home(&settings.storage_root);
+        settings.private_key = expand_home(&settings.private_key);
+        settings.public_key = expand_home(&settings.public_key);
+        settings.log_file = expand_home(&settings.log_file);
+        settings.pid_file = expand_home(&settings.pid_file);
+        settings.passphrase_file = settings.passphrase_file.as_deref().map(expand_home);
+        Ok(settings)
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.bind
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port))
+            .collect()
+    }
+
+    /// Read the private key of the server, asking for the passphrase if the key is protected
+    /// by one
+    pub fn unlock_private_key(&self) -> Result<SecretKey, HermodError> {
+        self.unlock_key(&self.private_key, &mut None)
+    }
+
+    /// Read a private key file of the server. The passphrase is only asked for if the key is
+    /// protected by one and `passphrase` is not already set, it is kept in `passphrase` so that
+    /// it is asked for once when unlocking several keys.
+    pub fn unlock_key(
+        &self,
+        path: &Path,
+        passphrase: &mut Option<Zeroizing<String>>,
+    ) -> Result<SecretKey, HermodError> {
+        permissions::check_private_file(path)?;
+        let content = std::fs::read_to_string(path)
+            .map(Zeroizing::new)
+            .map_err(|e| HermodError::new(HermodErrorKind::ReadPrivKey(e)))?;
+        if passphrase::is_encrypted(&content) {
+            if passphrase.is_none() {
+                *passphrase = Some(passphrase::read(
+                    "Enter passphrase for the server key: ",
+                    self.passphrase_file.as_deref(),
+                )?);
+            }
+            passphrase::decrypt(&content, passphrase.as_ref().unwrap())
+        } else {
+            let key = Zeroizing::new(base64::decode(content.trim())?);
+            Ok(SecretKey::new(&key))
+        }
+    }
+
+    /// Path of the private key replaced by the last key rotation
+    pub fn previous_private_key(&self) -> PathBuf {
+        let mut path = self.private_key.clone().into_os_string();
+        path.push(PREVIOUS_KEY_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    pub fn log_level(&self) -> Result<log::LevelFilter, HermodError> {
+        self.log_level.parse().map_err(|_| {
+            let msg = format!("Unknown log level: {}", self.log_level);
+            HermodError::new(HermodErrorKind::Config(msg))
+        })
+    }
+
+    /// Check the settings for problems that would stop the server from running
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.bind.is_empty() {
+            problems.push(String::from("No bind address given"));
+        }
+        if self.port == 0 {
+            problems.push(String::from("The port must not be 0"));
+        }
+        if self.max_connections == 0 {
+            problems.push(String::from("max_connections must be at least 1"));
+        }
+        if let Err(e) = self.log_level() {
+            problems.push(e.to_string());
+        }
+        if self.suites.is_empty() {
+            problems.push(String::from("suites must name at least one cipher suite"));
+        }
+        if !self.storage_root.is_dir() {
+            problems.push(format!(
+                "The storage root {} is not a directory",
+                self.storage_root.display()
+            ));
+        }
+        match std::fs::read_to_string(&self.private_key).map(Zeroizing::new) {
+            Ok(content) if passphrase::is_encrypted(&content) => {
+                if let Err(e) = passphrase::check(&content) {
+                    problems.push(format!("{}: {}", self.private_key.display(), e));
+                }
+                if let Some(ref file) = self.passphrase_file {
+                    if !file.is_file() {
+                        problems.push(format!(
+                            "The passphrase file {} does not exist",
+                            file.display()
+                        ));
+                    }
+                }
+            }
+            Ok(content) => match base64::decode(content.trim()).map(Zeroizing::new) {
+                Ok(key) if key.len() == 32 => (),
+                Ok(_) => problems.push(format!(
+                    "{}: invalid key length",
+                    self.private_key.display()
+                )),
+                Err(e) => problems.push(format!("{}: {}", self.private_key.display(), e)),
+            },
+            Err(e) => problems.push(format!("{}: {}", self.private_key.display(), e)),
+        }
+        if self.private_key.exists() {
+            if let Err(e) = permissions::check_private_file(&self.private_key) {
+                problems.push(e.to_string());
+            }
+        }
+        match read_key(&self.public_key) {
+            Ok(key) if key.len() == 32 => (),
+            Ok(_) => problems.push(format!("{}: invalid key length", self.public_key.display())),
+            Err(e) => problems.push(format!("{}: {}", self.public_key.display(), e)),
+        }
+        if let Some(ref ca_key) = self.ca_key {
+            match read_key(ca_key) {
+                Ok(key) if key.len() == 32 => (),
+                Ok(_) => problems.push(format!("{}: invalid key length", ca_key.display())),
+                Err(e) => problems.push(format!("{}: {}", ca_key.display(), e)),
+            }
+        }
+        for file in &[&self.log_file, &self.pid_file] {
+            match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    problems.push(format!("The directory {} does not exist", dir.display()))
+                }
+                _ => (),
+            }
+        }
+        problems
+    }
+}
+
+impl ClientSettings {
+    /// Read the settings for `alias` from the client configuration file.
+    /// A missing file is not an error, no settings are applied in that case.
+    pub fn load(alias: &str) -> Result<Self, HermodError> {
+        let path = hermod_dir().join(HERMOD_CLIENT_CONFIG_FILE);
+        if !path.exists() {
+            return Ok(ClientSettings::default());
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let mut file: ClientSettingsFile = toml::from_str(&content).map_err(|e| {
+            HermodError::new(HermodErrorKind::Config(format!(
+                "{}: {}",
+                path.display(),
+                e
+            )))
+        })?;
+
+        let settings = match file.host.remove(alias) {
+            Some(host) => host.merge(file.defaults),
+            None => file.defaults,
+        };
+        Ok(settings)
+    }
+
+    /// Fill in the settings missing from `self` with the ones from `defaults`
+    fn merge(self, defaults: ClientSettings) -> Self {
+        ClientSettings {
+            remote_dir: self.remote_dir.or(defaults.remote_dir),
+            port: self.port.or(defaults.port),
+            compression: self.compression.or(defaults.compression),
+            parallel: self.parallel.or(defaults.parallel),
+            rate_limit: self.rate_limit.or(defaults.rate_limit),
+            retries: self.retries.or(defaults.retries),
+            retry_delay: self.retry_delay.or(defaults.retry_delay),
+            suites: self.suites.or(defaults.suites),
+            post_quantum: self.post_quantum.or(defaults.post_quantum),
+        }
     }
 }
 
 impl ServerConfig {
     pub fn new() -> Self {
+        let settings = SERVER_SETTINGS.read().unwrap();
+
         let mut public_key = Vec::with_capacity(SERVER_KEY_SIZE);
-        let mut private_key = Vec::with_capacity(SERVER_KEY_SIZE);
 
-        let read_key_file = |buffer: &mut Vec<u8>, file_name: &str| -> io::Result<()> {
-            let mut path = PathBuf::new();
-            path.push(dirs::home_dir().expect("Failed to get home directory"));
-            path.push(HERMOD_BASE_DIR);
-            path.push(file_name);
+        let read_key_file = |buffer: &mut Vec<u8>, path: &Path| -> io::Result<()> {
             let mut f = File::open(path)?;
             f.read_to_end(buffer)?;
             Ok(())
         };
 
-        read_key_file(&mut public_key, SERVER_PUBLIC_KEY_FILE)
+        read_key_file(&mut public_key, &settings.public_key)
             .expect("Failed to read servers public key");
-        read_key_file(&mut private_key, SERVER_PRIVATE_KEY_FILE)
-            .expect("Failed to read servers private key");
 
         let public_key = base64::decode(&public_key).unwrap();
-        let private_key = base64::decode(&private_key).unwrap();
+        let mut passphrase = None;
+        let private_key = settings
+            .unlock_key(&settings.private_key, &mut passphrase)
+            .unwrap_or_else(|e| panic!("Failed to read servers private key: {}", e));
+
+        // The previous key is accepted until the grace period of the last rotation ends
+        let key_rotation = rotation::read_rotation()
+            .unwrap_or_else(|e| panic!("{}", e))
+            .filter(|rotation| !rotation.is_expired());
+        let previous_private_key = key_rotation.as_ref().and_then(|_| {
+            let path = settings.previous_private_key();
+            settings
+                .unlock_key(&path, &mut passphrase)
+                .map_err(|e| warn!("Not accepting the previous server key: {}", e))
+                .ok()
+        });
+
+        let ca_key = settings.ca_key.as_ref().map(|path| {
+            read_key(path)
+                .unwrap_or_else(|e| panic!("Failed to read the certificate authority key: {}", e))
+        });
 
         ServerConfig {
             public_key,
             private_key,
+            previous_private_key,
+            key_rotation,
+            ca_key,
         }
     }
+
+    /// Public key of the certificate authority trusted by the server, if any
+    pub fn ca_key(&self) -> Option<&[u8]> {
+        self.ca_key.as_deref()
+    }
+
+    /// The announcement of the last key rotation, if it was active when the server started
+    pub fn key_rotation(&self) -> Option<&KeyRotation> {
+        self.key_rotation.as_ref()
+    }
 }
 
 impl<'builder> ClientConfig<'builder> {
@@ -135,6 +608,16 @@ impl<'builder> ClientConfig<'builder> {
             source,
             destination,
             request,
+            filter: Filter::default(),
+            remote_dir: None,
+            port: None,
+            compression: false,
+            parallel: 1,
+            rate_limit: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(HERMOD_RETRY_DELAY),
+            suites: vec![Suite::default()],
+            post_quantum: false,
         }
     }
 
@@ -142,7 +625,53 @@ impl<'builder> ClientConfig<'builder> {
         &self.host.hostname()
     }
 
+    /// The address to connect to, using the configured port instead of the one in the host file
+    pub fn address(&self) -> String {
+        let port = self.port.unwrap_or_else(|| self.host.port());
+        host::address(self.host.hostname(), port)
+    }
+
+    /// Resolve a remote path against the configured remote directory
+    pub fn remote_path(&self, path: &str) -> String {
+        let path = match self.remote_dir {
+            Some(dir) if Path::new(path).is_relative() => {
+                Path::new(dir).join(path).to_string_lossy().into_owned()
+            }
+            _ => path.to_owned(),
+        };
+        if path.is_empty() {
+            String::from(".")
+        } else {
+            path
+        }
+    }
+
     pub fn get_alias(&self) -> &str {
         &self.host.alias()
     }
+
+    pub fn host(&self) -> &Host {
+        self.host
+    }
+}
+
+fn hermod_dir() -> PathBuf {
+    let mut path = dirs::home_dir().expect("Failed to get home directory");
+    path.push(HERMOD_BASE_DIR);
+    path
+}
+
+/// Expand a leading '~' to the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => dirs::home_dir()
+            .expect("Failed to get home directory")
+            .join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+fn read_key(path: &Path) -> Result<Vec<u8>, HermodError> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(base64::decode(content.trim())?)
 }
@@ -7,6 +7,8 @@ pub const MSG_TYPE_LEN: usize = 1;
 pub const MSG_LENGTH_LEN: usize = 2;
 pub const MSG_HEADER_LEN: usize = MSG_TYPE_LEN + MSG_LENGTH_LEN;
 pub const MSG_PAYLOAD_LEN: usize = PACKET_MAXLENGTH - MSG_HEADER_LEN - MAC_LENGTH;
+// Leaves room for the deflate overhead when compressing data that does not compress
+pub const MSG_COMPRESSED_CHUNK_LEN: usize = MSG_PAYLOAD_LEN - 1024;
 
 pub const AEAD_TAG_LEN: usize = 16;
 pub const REKEY_THRESHOLD: usize = 1_073_741_824; // 1 GB
@@ -15,7 +17,27 @@ pub const ID_TOKEN_LEN: u8 = 8;
 pub const ID_TOKEN_B64LEN: u8 = 12;
 
 pub const NOISE_PATTERN: &str = "Noise_KK_25519_ChaChaPoly_BLAKE2s";
+// The client sends its static key encrypted, instead of its id token in the clear.
+// Combined with the negotiated cipher suite.
+pub const IDENTITY_HIDING_PATTERN: &str = "IK";
+// Mixes a pre-shared key into the second handshake message, for clients that have one
+pub const IDENTITY_HIDING_PSK_PATTERN: &str = "IKpsk2";
+pub const PSK_LEN: usize = 32;
+// Flags of the first handshake message of the identity hiding handshake
+pub const HANDSHAKE_PSK: u8 = 1;
+// An ML-KEM-768 secret is mixed in like a pre-shared key
+pub const HANDSHAKE_HYBRID: u8 = 2;
+pub const HYBRID_KEY_LEN: usize = 1184;
+pub const HYBRID_CIPHERTEXT_LEN: usize = 1088;
+// Cookies a server under load asks clients to present before their handshake
+pub const COOKIE_LEN: usize = 16;
+pub const COOKIE_SECRET_LIFETIME: u64 = 120; // seconds
+pub const HERMOD_MAX_HANDSHAKE_SOURCES: usize = 10000;
+// Time a client has to send its part of a handshake
+pub const HERMOD_HANDSHAKE_TIMEOUT: u64 = 10; // seconds
 pub const SHARE_KEY_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// The key agreed on when pairing authenticates the server's second handshake message
+pub const PAIR_PATTERN: &str = "Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s";
 
 pub const HERMOD_BASE_DIR: &str = ".hermod";
 
@@ -23,12 +45,49 @@ pub const SERVER_PRIVATE_KEY_FILE: &str = "server_key";
 pub const SERVER_PUBLIC_KEY_FILE: &str = "server_key.pub";
 
 pub const SERVER_KEY_SIZE: usize = 44;
+// Appended to the name of the private key file to keep the key replaced by a rotation
+pub const PREVIOUS_KEY_SUFFIX: &str = ".old";
+pub const HERMOD_KEY_ROTATION_FILE: &str = "key_rotation";
+pub const HERMOD_KEY_GRACE_PERIOD: i64 = 30; // days
+pub const HERMOD_CA_PRIVATE_KEY_FILE: &str = "ca_key";
+pub const HERMOD_CA_PUBLIC_KEY_FILE: &str = "ca_key.pub";
+pub const HERMOD_CERTIFICATE_VALIDITY: i64 = 90; // days
 
 pub const HERMOD_LOG_FILE: &str = "server.log";
+pub const HERMOD_SERVER_CONFIG_FILE: &str = "server.toml";
+pub const HERMOD_PID_FILE: &str = "/tmp/hermod.pid";
+
+pub const HERMOD_CLIENTS_FILE: &str = "authorized_clients";
+pub const HERMOD_PENDING_CLIENTS_FILE: &str = "pending_clients";
+pub const HERMOD_MAX_PENDING_CLIENTS: usize = 100;
+pub const HERMOD_CLIENTS_POLL_INTERVAL: u64 = 2; // seconds
+pub const HERMOD_CLIENT_CONFIG_FILE: &str = "config";
+pub const HERMOD_INVITES_FILE: &str = "invites";
+pub const HERMOD_INVITE_VALIDITY: i64 = 24; // hours
+pub const INVITE_CODE_LEN: usize = 16;
+pub const HERMOD_PAIR_VALIDITY: i64 = 10; // minutes
+pub const HERMOD_RETRY_DELAY: u64 = 1; // seconds
+
+pub const HERMOD_IGNORE_FILE: &str = ".hermodignore";
+
+pub const HERMOD_PASSPHRASE_VAR: &str = "HERMOD_PASSPHRASE";
+pub const HERMOD_PASSPHRASE_FILE_VAR: &str = "HERMOD_PASSPHRASE_FILE";
+pub const ENCRYPTED_KEY_PREFIX: &str = "hermod-enc-v1:";
 
 pub const HERMOD_HS_INIT_LEN: usize = 61;
 pub const HERMOD_HS_RESP_LEN: usize = 48;
+// The ephemeral key, the encrypted static key and the tag of the payload
+pub const HERMOD_HS_IDENTITY_INIT_LEN: usize = 96;
+pub const HERMOD_MAX_CERTIFICATE_LEN: usize = 4096;
 
 pub const HERMOD_KS_INIT_LEN: usize = 32;
 pub const HERMOD_KS_RES1_LEN: usize = 96 + MSG_TYPE_LEN;
-pub const HERMOD_KS_RES2_LEN: usize = 76 + MSG_TYPE_LEN;
+pub const HERMOD_KS_RES2_LEN: usize = 76 + INVITE_CODE_LEN + MSG_TYPE_LEN;
+
+pub const PAIR_CLIENT_ID: &[u8] = b"hermod-client";
+pub const PAIR_SERVER_ID: &[u8] = b"hermod-server";
+pub const SPAKE2_MSG_LEN: usize = 33;
+pub const HERMOD_PAIR_INIT_LEN: usize = 2 + SPAKE2_MSG_LEN;
+pub const HERMOD_PAIR_RESP_LEN: usize = SPAKE2_MSG_LEN + MSG_TYPE_LEN;
+// The ephemeral key and the tag of the empty payload, encrypted since the handshake has a psk
+pub const HERMOD_KS_PSK_INIT_LEN: usize = 48;
@@ -0,0 +1,221 @@
+use crate::consts::*;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use rand_core::{OsRng, RngCore};
+
+use sha2::{Digest, Sha256};
+
+use zeroize::Zeroizing;
+
+/// Issues and checks the cookies a client must present before its handshake when the server is
+/// under load. A cookie is a MAC of the address of the client under a secret that is replaced
+/// every two minutes, so the server keeps no state per client.
+pub struct CookieSecret {
+    current: Zeroizing<[u8; 32]>,
+    previous: Zeroizing<[u8; 32]>,
+    changed: Instant,
+}
+
+impl CookieSecret {
+    pub fn new() -> Self {
+        CookieSecret {
+            current: random_secret(),
+            previous: random_secret(),
+            changed: Instant::now(),
+        }
+    }
+
+    /// The cookie for `addr`, valid until the secret has been replaced twice
+    pub fn cookie(&mut self, addr: IpAddr) -> [u8; COOKIE_LEN] {
+        self.rotate();
+        mac(&self.current[..], addr)
+    }
+
+    /// Whether `cookie` was issued for `addr` with the current or the previous secret
+    pub fn check(&mut self, addr: IpAddr, cookie: &[u8]) -> bool {
+        self.rotate();
+        [&self.current[..], &self.previous[..]]
+            .iter()
+            .any(|secret| constant_time_eq(&mac(secret, addr), cookie))
+    }
+
+    fn rotate(&mut self) {
+        let lifetime = Duration::from_secs(COOKIE_SECRET_LIFETIME);
+        let elapsed = self.changed.elapsed();
+        if elapsed < lifetime {
+            return;
+        }
+        // Cookies issued with a secret older than two lifetimes are not accepted
+        self.previous = if elapsed < 2 * lifetime {
+            self.current.clone()
+        } else {
+            random_secret()
+        };
+        self.current = random_secret();
+        self.changed = Instant::now();
+    }
+}
+
+impl Default for CookieSecret {
+    fn default() -> Self {
+        CookieSecret::new()
+    }
+}
+
+/// Limits the handshakes started from each address, to a rate per minute with bursts of as many.
+/// IPv6 addresses are counted by their /64 prefix, the block a single host is usually given.
+pub struct HandshakeLimiter {
+    // Available handshakes and when they were last counted, by address or prefix
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+    // When the buckets of a full table were last looked through for ones to forget
+    pruned: Option<Instant>,
+}
+
+impl HandshakeLimiter {
+    pub fn new() -> Self {
+        HandshakeLimiter {
+            buckets: HashMap::new(),
+            pruned: None,
+        }
+    }
+
+    /// Whether `addr` may start another handshake at `now`, `rate` handshakes per minute are
+    /// allowed and 0 allows any number. While the table of sources is full new sources are
+    /// refused.
+    pub fn allow(&mut self, addr: IpAddr, rate: u32, now: Instant) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        let capacity = f64::from(rate);
+        let refill = |tokens: f64, last: Instant| {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            (tokens + elapsed * capacity / 60.0).min(capacity)
+        };
+        let source = source(addr);
+        if self.buckets.len() >= HERMOD_MAX_HANDSHAKE_SOURCES && !self.buckets.contains_key(&source)
+        {
+            // Addresses that have waited long enough to start a full burst again are forgotten,
+            // looking for them at most once a second
+            let due = self
+                .pruned
+                .is_none_or(|pruned| now.saturating_duration_since(pruned).as_secs() >= 1);
+            if due {
+                self.buckets
+                    .retain(|_, (tokens, last)| refill(*tokens, *last) < capacity);
+                self.pruned = Some(now);
+            }
+            if self.buckets.len() >= HERMOD_MAX_HANDSHAKE_SOURCES {
+                return false;
+            }
+        }
+
+        let (tokens, last) = self.buckets.entry(source).or_insert((capacity, now));
+        *tokens = refill(*tokens, *last);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for HandshakeLimiter {
+    fn default() -> Self {
+        HandshakeLimiter::new()
+    }
+}
+
+/// The address or prefix the handshakes of `addr` are counted for
+fn source(addr: IpAddr) -> IpAddr {
+    match addr {
+        // Clients of a dual-stack listener appear with IPv4-mapped addresses
+        IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+            Some(addr) => IpAddr::V4(addr),
+            None => {
+                let mut octets = addr.octets();
+                octets[8..].iter_mut().for_each(|octet| *octet = 0);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        },
+        addr => addr,
+    }
+}
+
+fn random_secret() -> Zeroizing<[u8; 32]> {
+    let mut secret = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut *secret);
+    secret
+}
+
+fn mac(secret: &[u8], addr: IpAddr) -> [u8; COOKIE_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.input(b"hermod-cookie");
+    hasher.input(secret);
+    match addr {
+        IpAddr::V4(addr) => hasher.input(addr.octets()),
+        IpAddr::V6(addr) => hasher.input(addr.octets()),
+    }
+    let mut cookie = [0u8; COOKIE_LEN];
+    cookie.copy_from_slice(&hasher.result()[..COOKIE_LEN]);
+    cookie
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_cookies_and_limit_handshakes() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+
+        let mut secret = CookieSecret::new();
+        let cookie = secret.cookie(client);
+        assert!(secret.check(client, &cookie));
+        assert!(!secret.check(other, &cookie));
+        assert!(!secret.check(client, &cookie[1..]));
+
+        let mut limiter = HandshakeLimiter::new();
+        let now = Instant::now();
+        assert!((0..3).all(|_| limiter.allow(client, 3, now)));
+        assert!(!limiter.allow(client, 3, now));
+        assert!(limiter.allow(other, 3, now));
+
+        // Addresses of the same /64 share their handshakes, IPv4-mapped ones count as IPv4
+        let neighbour: IpAddr = "2001:db8::2".parse().unwrap();
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert!((0..2).all(|_| limiter.allow(neighbour, 3, now)));
+        assert!(!limiter.allow(other, 3, now));
+        assert!(limiter.allow("2001:db8:0:1::1".parse().unwrap(), 3, now));
+        assert!(!limiter.allow(mapped, 3, now));
+        assert!(limiter.allow(client, 3, now + Duration::from_secs(20)));
+        assert!(limiter.allow(client, 0, now));
+    }
+
+    #[test]
+    fn refuse_new_sources_while_the_table_is_full() {
+        let mut limiter = HandshakeLimiter::new();
+        let now = Instant::now();
+        let source = |i: usize| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+        let full = HERMOD_MAX_HANDSHAKE_SOURCES;
+        assert!((0..full).all(|i| limiter.allow(source(i), 3, now)));
+
+        // Every bucket is partly used, known sources are still counted
+        assert!(!limiter.allow(source(full), 3, now));
+        assert!(limiter.allow(source(0), 3, now));
+        assert_eq!(limiter.buckets.len(), full);
+
+        // Once they have refilled the buckets are forgotten
+        assert!(limiter.allow(source(full), 3, now + Duration::from_secs(60)));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+}
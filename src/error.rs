@@ -1,6 +1,8 @@
 use std::error;
 use std::fmt;
 
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug)]
 pub struct HermodError(Box<HermodErrorKind>);
 #[derive(Debug)]
@@ -22,9 +24,97 @@ pub enum HermodErrorKind {
     Snow(snow::error::Error),
     ShareKey,
     PathExists,
+    InvalidPattern(String),
+    Config(String),
+    HostFile(String),
+    ClientsFile(String),
+    UnknownClient(String),
+    ClientExists(String),
+    InvalidClient(String),
+    Revoked,
+    Passphrase(String),
+    InsecurePermissions(String),
+    Fingerprint(String),
+    InvalidInvite,
+    EnrollmentRejected,
+    Pairing(String),
+    KeyRotation(String),
+    Certificate(String),
+    AccessDenied(String),
+    CipherSuite(String),
+    Hybrid(String),
+    ServerBusy,
+    Remote(RemoteError),
+    Other,
+}
+
+/// Error reported by the remote peer when it fails to carry out a request
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RemoteError {
+    kind: RemoteErrorKind,
+    message: String,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RemoteErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    InvalidRequest,
     Other,
 }
 
+impl RemoteError {
+    pub fn new(kind: RemoteErrorKind, message: &str) -> Self {
+        RemoteError {
+            kind,
+            message: message.to_owned(),
+        }
+    }
+
+    pub fn kind(&self) -> RemoteErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            RemoteErrorKind::NotFound => write!(f, "No such file or directory: {}", self.message),
+            RemoteErrorKind::PermissionDenied => write!(f, "Permission denied: {}", self.message),
+            RemoteErrorKind::AlreadyExists => write!(f, "Path exists: {}", self.message),
+            RemoteErrorKind::InvalidRequest => write!(f, "Invalid request: {}", self.message),
+            RemoteErrorKind::Other => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl RemoteError {
+    /// Describe a failed file system operation on `path`
+    pub fn from_io(err: &std::io::Error, path: &std::path::Path) -> RemoteError {
+        let path = path.display().to_string();
+        match err.kind() {
+            std::io::ErrorKind::NotFound => RemoteError::new(RemoteErrorKind::NotFound, &path),
+            std::io::ErrorKind::PermissionDenied => {
+                RemoteError::new(RemoteErrorKind::PermissionDenied, &path)
+            }
+            std::io::ErrorKind::AlreadyExists => {
+                RemoteError::new(RemoteErrorKind::AlreadyExists, &path)
+            }
+            _ => {
+                // Prefer the underlying OS error over any context added by async_std
+                let reason = error::Error::source(err)
+                    .map_or_else(|| err.to_string(), |source| source.to_string());
+                RemoteError::new(RemoteErrorKind::Other, &format!("{}: {}", path, reason))
+            }
+        }
+    }
+}
+
 impl HermodError {
     pub fn new(kind: HermodErrorKind) -> HermodError {
         HermodError(Box::new(kind))
@@ -37,6 +127,25 @@ impl HermodError {
     pub fn into_kind(self) -> HermodErrorKind {
         *self.0
     }
+
+    /// Whether the error was caused by losing, or failing to establish, the connection
+    pub fn is_connection_error(&self) -> bool {
+        match *self.0 {
+            HermodErrorKind::Snow(_)
+            | HermodErrorKind::ConnectionRefused(_)
+            | HermodErrorKind::ServerBusy => true,
+            HermodErrorKind::IoError(ref err) => matches!(
+                err.kind(),
+                std::io::ErrorKind::UnexpectedEof
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for HermodError {
@@ -71,6 +180,52 @@ impl fmt::Display for HermodError {
             HermodErrorKind::Snow(ref err) => write!(f, "{}", err),
             HermodErrorKind::ShareKey => write!(f, "Failed to share identity"),
             HermodErrorKind::PathExists => write!(f, "Path Exists"),
+            HermodErrorKind::InvalidPattern(ref err) => write!(f, "Invalid pattern: {}", err),
+            HermodErrorKind::Config(ref err) => write!(f, "Invalid configuration: {}", err),
+            HermodErrorKind::HostFile(ref err) => write!(f, "Invalid host file: {}", err),
+            HermodErrorKind::ClientsFile(ref err) => {
+                write!(f, "Invalid authorized_clients file: {}", err)
+            }
+            HermodErrorKind::UnknownClient(ref name) => {
+                write!(f, "No client with the id or label '{}'", name)
+            }
+            HermodErrorKind::ClientExists(ref name) => {
+                write!(f, "A client with the id or label '{}' already exists", name)
+            }
+            HermodErrorKind::InvalidClient(ref err) => write!(f, "Invalid client: {}", err),
+            HermodErrorKind::Revoked => write!(f, "The access of the client was revoked"),
+            HermodErrorKind::Passphrase(ref err) => {
+                write!(f, "Failed to unlock the private key: {}", err)
+            }
+            HermodErrorKind::InsecurePermissions(ref file) => write!(
+                f,
+                "Permissions of {} are too open, it must only be accessible by its owner",
+                file
+            ),
+            HermodErrorKind::Fingerprint(ref err) => {
+                write!(f, "Failed to verify the server key: {}", err)
+            }
+            HermodErrorKind::InvalidInvite => {
+                write!(f, "The invitation code is unknown, expired or already used")
+            }
+            HermodErrorKind::EnrollmentRejected => write!(
+                f,
+                "The server rejected the enrollment, check that the invitation code is valid"
+            ),
+            HermodErrorKind::Pairing(ref err) => write!(f, "Pairing failed: {}", err),
+            HermodErrorKind::KeyRotation(ref err) => {
+                write!(f, "Invalid server key rotation: {}", err)
+            }
+            HermodErrorKind::Certificate(ref err) => write!(f, "Invalid certificate: {}", err),
+            HermodErrorKind::AccessDenied(ref err) => write!(f, "Access denied: {}", err),
+            HermodErrorKind::CipherSuite(ref err) => {
+                write!(f, "Failed to agree on a cipher suite: {}", err)
+            }
+            HermodErrorKind::Hybrid(ref err) => {
+                write!(f, "Post-quantum key exchange failed: {}", err)
+            }
+            HermodErrorKind::ServerBusy => write!(f, "The server is under load, try again later"),
+            HermodErrorKind::Remote(ref err) => write!(f, "Remote error: {}", err),
             HermodErrorKind::Other => write!(f, "Unspecified error"),
         }
     }
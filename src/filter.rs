@@ -0,0 +1,90 @@
+use crate::consts::*;
+use crate::error::{HermodError, HermodErrorKind};
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+
+use serde::{Deserialize, Serialize};
+
+/// Selects which files of a directory tree are transferred.
+/// Sent along with the request so that the side enumerating the tree can apply it.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    ignore_files: bool,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Filter::default()
+    }
+
+    /// Only transfer files matching at least one of the patterns
+    pub fn include(mut self, patterns: &[&str]) -> Self {
+        self.include
+            .extend(patterns.iter().map(|pattern| pattern.to_string()));
+        self
+    }
+
+    /// Skip files and directories matching any of the patterns
+    pub fn exclude(mut self, patterns: &[&str]) -> Self {
+        self.exclude
+            .extend(patterns.iter().map(|pattern| pattern.to_string()));
+        self
+    }
+
+    /// Respect .gitignore and .hermodignore files found in the tree and its parents
+    pub fn ignore_files(mut self, enabled: bool) -> Self {
+        self.ignore_files = enabled;
+        self
+    }
+
+    /// List all files below `root` that pass the filter, fails if part of the tree can not be
+    /// read so that no file is left out silently
+    pub fn walk(&self, root: &Path) -> Result<Vec<PathBuf>, HermodError> {
+        let mut overrides = OverrideBuilder::new(root);
+        for pattern in &self.include {
+            overrides.add(pattern).map_err(invalid_pattern)?;
+        }
+        for pattern in &self.exclude {
+            overrides
+                .add(&format!("!{}", pattern))
+                .map_err(invalid_pattern)?;
+        }
+        let overrides = overrides.build().map_err(invalid_pattern)?;
+
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .standard_filters(false)
+            .git_ignore(self.ignore_files)
+            .parents(self.ignore_files)
+            .require_git(false)
+            .overrides(overrides);
+        if self.ignore_files {
+            builder.add_custom_ignore_filename(HERMOD_IGNORE_FILE);
+        }
+
+        let mut files = Vec::new();
+        for entry in builder.build() {
+            let entry = entry.map_err(walk_error)?;
+            // Links to directories are skipped like the directories themselves
+            if !entry.path().is_dir() {
+                files.push(entry.into_path());
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn walk_error(err: ignore::Error) -> HermodError {
+    let kind = err.io_error().map_or(io::ErrorKind::Other, io::Error::kind);
+    io::Error::new(kind, err).into()
+}
+
+fn invalid_pattern(err: ignore::Error) -> HermodError {
+    HermodError::new(HermodErrorKind::InvalidPattern(err.to_string()))
+}
@@ -1,16 +1,54 @@
 use crate::consts::*;
+use crate::secret::SecretKey;
 
 use rand::prelude::*;
+use sha2::{Digest, Sha256};
 use snow::Builder;
+use zeroize::Zeroize;
 
-pub fn create_server_keys() -> Result<snow::Keypair, snow::error::Error> {
+/// A static key pair, the private key is kept in locked memory
+pub struct KeyPair {
+    pub public: Vec<u8>,
+    pub private: SecretKey,
+}
+
+impl From<snow::Keypair> for KeyPair {
+    fn from(mut keys: snow::Keypair) -> Self {
+        let private = SecretKey::new(&keys.private);
+        keys.private.zeroize();
+        KeyPair {
+            public: keys.public,
+            private,
+        }
+    }
+}
+
+pub fn create_server_keys() -> Result<KeyPair, snow::error::Error> {
     let builder = Builder::new(NOISE_PATTERN.parse()?);
-    builder.generate_keypair()
+    builder.generate_keypair().map(KeyPair::from)
 }
 
-pub fn gen_keys() -> Result<snow::Keypair, snow::error::Error> {
+pub fn gen_keys() -> Result<KeyPair, snow::error::Error> {
     let builder = Builder::new(NOISE_PATTERN.parse()?);
-    builder.generate_keypair()
+    builder.generate_keypair().map(KeyPair::from)
+}
+
+/// Fingerprint of a public key, in the same format as OpenSSH uses: `SHA256:<base64>`
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    format!(
+        "SHA256:{}",
+        base64::encode_config(&digest, base64::STANDARD_NO_PAD)
+    )
+}
+
+/// A random key for the pre-shared key mode of the handshake
+pub fn gen_psk() -> SecretKey {
+    let mut psk = [0u8; PSK_LEN];
+    rand::thread_rng().fill_bytes(&mut psk);
+    let key = SecretKey::new(&psk);
+    psk.zeroize();
+    key
 }
 
 pub fn gen_idtoken() -> String {
@@ -1,3 +1,5 @@
+use crate::secret::SecretKey;
+
 use std::convert::TryInto;
 
 use skymd::{chacha20poly1305, curve25519};
@@ -35,7 +37,7 @@ impl CryptoResolver for HaclResolver {
 }
 
 pub struct HaclCurve25519 {
-    privkey: curve25519::PrivateKey,
+    privkey: SecretKey,
     pubkey: curve25519::PublicKey,
 }
 
@@ -44,7 +46,7 @@ impl Default for HaclCurve25519 {
         skymd::skymd_init();
 
         Self {
-            privkey: curve25519::PrivateKey::default(),
+            privkey: SecretKey::new(curve25519::PrivateKey::default().as_bytes()),
             pubkey: curve25519::PublicKey::default(),
         }
     }
@@ -64,10 +66,11 @@ impl Dh for HaclCurve25519 {
     }
 
     fn set(&mut self, privkey: &[u8]) {
-        self.privkey = curve25519::PrivateKey::from_slice(
+        let privkey = curve25519::PrivateKey::from_slice(
             privkey.try_into().expect("Wrong length on the private key"),
         );
-        self.pubkey = curve25519::PublicKey::from(&self.privkey);
+        self.pubkey = curve25519::PublicKey::from(&privkey);
+        self.privkey = SecretKey::new(privkey.as_bytes());
     }
 
     fn pubkey(&self) -> &[u8] {
@@ -81,38 +84,52 @@ impl Dh for HaclCurve25519 {
     fn generate(&mut self, _rng: &mut dyn Random) {
         let (pubkey, privkey) = curve25519::keypair();
         self.pubkey = pubkey;
-        self.privkey = privkey;
+        self.privkey = SecretKey::new(privkey.as_bytes());
     }
 
     fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
         let pubkey: [u8; curve25519::KEY_LEN] =
             pubkey[..32].try_into().expect("Wrong length on public key");
         let pubkey = curve25519::PublicKey::from(pubkey);
-        let shared_secret = self.privkey.ecdh(&pubkey);
+        // The temporary private key is wiped when dropped
+        let privkey = curve25519::PrivateKey::from_slice(
+            self.privkey
+                .as_bytes()
+                .try_into()
+                .expect("Wrong length on the private key"),
+        );
+        let shared_secret = privkey.ecdh(&pubkey);
         out[..curve25519::KEY_LEN].copy_from_slice(&shared_secret.as_bytes());
         Ok(())
     }
 }
 
 pub struct HaclChachaPoly {
-    key: [u8; chacha20poly1305::KEY_LEN],
+    key: SecretKey,
 }
 impl Default for HaclChachaPoly {
     fn default() -> Self {
         skymd::skymd_init();
         Self {
-            key: [0u8; chacha20poly1305::KEY_LEN],
+            key: SecretKey::new(&[0u8; chacha20poly1305::KEY_LEN]),
         }
     }
 }
 
+impl HaclChachaPoly {
+    fn key(&self) -> &[u8; chacha20poly1305::KEY_LEN] {
+        self.key.as_bytes().try_into().expect("wrong size on key")
+    }
+}
+
 impl Cipher for HaclChachaPoly {
     fn name(&self) -> &'static str {
         "ChaChaPoly"
     }
 
     fn set(&mut self, key: &[u8]) {
-        self.key = key.try_into().expect("wrong size on key");
+        assert_eq!(key.len(), chacha20poly1305::KEY_LEN, "wrong size on key");
+        self.key = SecretKey::new(key);
     }
 
     fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
@@ -124,7 +141,7 @@ impl Cipher for HaclChachaPoly {
 
         let mut tag = [0u8; chacha20poly1305::TAG_LEN];
 
-        chacha20poly1305::encrypt(&self.key, &nonce_bytes, authtext, plaintext, out, &mut tag);
+        chacha20poly1305::encrypt(self.key(), &nonce_bytes, authtext, plaintext, out, &mut tag);
 
         out[plaintext.len()..plaintext.len() + chacha20poly1305::TAG_LEN].copy_from_slice(&tag);
         cipher_len
@@ -149,7 +166,7 @@ impl Cipher for HaclChachaPoly {
             .expect("failed to get tag from ciphertext");
 
         let result = chacha20poly1305::decrypt(
-            &self.key,
+            self.key(),
             &nonce_bytes,
             authtext,
             out,
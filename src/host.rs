@@ -1,24 +1,69 @@
+use crate::certificate::Certificate;
 use crate::consts::*;
-use crate::error::HermodError;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::passphrase;
+use crate::permissions;
+use crate::secret::SecretKey;
 
 use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use std::io::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use async_std::fs::File;
-use async_std::io::{self, BufReader};
-use async_std::path::PathBuf;
-use async_std::prelude::*;
+use zeroize::{Zeroize, Zeroizing};
 
 static HOST_DIR: &str = "known_hosts";
 
+/// Version of the host file format written by `write_to_file`
+const HOST_FILE_VERSION: u32 = 1;
+
+#[derive(Clone)]
 pub struct Host {
     pub alias: String,
     pub hostname: String,
+    pub port: u16,
     pub id_token: String,
     pub public_key: Vec<u8>,
-    pub private_key: Vec<u8>,
+    pub private_key: SecretKey,
     pub server_key: Vec<u8>,
+    pub certificate: Option<Box<Certificate>>,
+    pub psk: Option<SecretKey>,
+    // The private key as stored on disk when it is protected by a passphrase
+    encrypted_private_key: Option<String>,
+}
+
+/// On disk representation of a host, keys are stored base64 encoded
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostFile {
+    version: u32,
+    hostname: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    id_token: String,
+    public_key: String,
+    private_key: String,
+    #[serde(default)]
+    server_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    psk: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    certificate: Option<Certificate>,
+}
+
+impl Drop for HostFile {
+    fn drop(&mut self) {
+        self.private_key.zeroize();
+        self.psk.zeroize();
+    }
+}
+
+/// A problem found while parsing a host file, `line` is 1-based
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    line: usize,
+    message: String,
 }
 
 impl fmt::Display for Host {
@@ -34,10 +79,14 @@ impl Host {
         Host {
             alias: String::from(alias),
             hostname: String::new(),
+            port: HERMOD_PORT,
             id_token: String::new(),
             public_key: Vec::new(),
-            private_key: Vec::new(),
+            private_key: SecretKey::default(),
             server_key: Vec::new(),
+            certificate: None,
+            psk: None,
+            encrypted_private_key: None,
         }
     }
 
@@ -46,26 +95,53 @@ impl Host {
         self
     }
 
+    pub fn set_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
     pub fn set_id_token(mut self, id: &str) -> Self {
         self.id_token.push_str(id);
         self
     }
 
     pub fn set_public_key(mut self, key: &[u8]) -> Self {
-        self.public_key.extend(key);
+        self.public_key = key.to_vec();
         self
     }
 
     pub fn set_private_key(mut self, key: &[u8]) -> Self {
-        self.private_key.extend(key);
+        self.private_key = SecretKey::new(key);
         self
     }
 
     pub fn set_server_key(mut self, key: &[u8]) -> Self {
-        self.server_key.extend(key);
+        self.server_key = key.to_vec();
+        self
+    }
+
+    /// Present `certificate` instead of the id token when connecting to the server
+    pub fn set_certificate(mut self, certificate: Option<Certificate>) -> Self {
+        self.certificate = certificate.map(Box::new);
+        self
+    }
+
+    /// Mix `psk` into the handshake with the server, which must have the same key for the client
+    pub fn set_psk(mut self, psk: Option<&[u8]>) -> Self {
+        self.psk = psk.map(SecretKey::new);
         self
     }
 
+    /// Protect the private key with a passphrase when written to file,
+    /// `None` stores the key unencrypted
+    pub fn set_passphrase(mut self, passphrase: Option<&str>) -> Result<Self, HermodError> {
+        self.encrypted_private_key = match passphrase {
+            Some(passphrase) => Some(passphrase::encrypt(self.private_key(), passphrase)?),
+            None => None,
+        };
+        Ok(self)
+    }
+
     pub fn alias(&self) -> &str {
         &self.alias
     }
@@ -74,12 +150,21 @@ impl Host {
         &self.hostname
     }
 
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The address of the server, suitable for connecting to
+    pub fn address(&self) -> String {
+        address(&self.hostname, self.port)
+    }
+
     pub fn id_token(&self) -> &str {
         &self.id_token
     }
 
     pub fn private_key(&self) -> &[u8] {
-        &self.private_key
+        self.private_key.as_bytes()
     }
 
     pub fn public_key(&self) -> &[u8] {
@@ -90,92 +175,330 @@ impl Host {
         &self.server_key
     }
 
+    pub fn certificate(&self) -> Option<&Certificate> {
+        self.certificate.as_deref()
+    }
+
+    pub fn psk(&self) -> Option<&[u8]> {
+        self.psk.as_ref().map(SecretKey::as_bytes)
+    }
+
     // Only used from sync blocks
     pub fn write_to_file(&self) -> io::Result<()> {
-        let mut path = PathBuf::new();
-        path.push(dirs::home_dir().unwrap());
-        path.push(HERMOD_BASE_DIR);
-        path.push(HOST_DIR);
-
-        std::fs::create_dir_all(&path).expect("Failed to crate known_hosts directory");
-
-        path.push(&self.alias);
-
-        let file = std::fs::File::create(path)?;
-        let mut writer = std::io::BufWriter::new(file);
-        writer.write_all(format!("Hostname: {}:{}\n", &self.hostname, HERMOD_PORT).as_bytes())?;
-        writer
-            .write_all(format!("PublicKey: {}\n", base64::encode(&self.public_key)).as_bytes())?;
-        writer
-            .write_all(format!("PrivateKey: {}\n", base64::encode(&self.private_key)).as_bytes())?;
-        writer.write_all(format!("IdToken: {}\n", &self.id_token).as_bytes())?;
-        writer
-            .write_all(format!("ServerKey: {}\n", base64::encode(&self.server_key)).as_bytes())?;
-        Ok(())
+        self.write_to(&host_path(&self.alias))
+    }
+
+    /// Keep the host with a new key next to its host file, until the server has replaced the
+    /// key and the host file is updated. Returns the path of the copy.
+    pub fn write_new_key(&self) -> io::Result<PathBuf> {
+        let path = host_path(&self.alias).with_file_name(format!("{}.new", self.alias));
+        self.write_to(&path)?;
+        Ok(path)
+    }
+
+    fn write_to(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            permissions::create_private_dir(dir).expect("Failed to crate known_hosts directory");
+        }
+
+        let file = HostFile {
+            version: HOST_FILE_VERSION,
+            hostname: self.hostname.clone(),
+            port: self.port,
+            id_token: self.id_token.clone(),
+            public_key: base64::encode(&self.public_key),
+            private_key: match self.encrypted_private_key {
+                Some(ref key) => key.clone(),
+                None => base64::encode(&self.private_key),
+            },
+            server_key: base64::encode(&self.server_key),
+            psk: self.psk().map(base64::encode),
+            certificate: self.certificate().cloned(),
+        };
+        let content = toml::to_string(&file)
+            .map(Zeroizing::new)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        permissions::write_private_file(path, content.as_bytes())
+    }
+}
+
+/// Join a hostname and a port, adding brackets around IPv6 addresses
+pub fn address(hostname: &str, port: u16) -> String {
+    if hostname.contains(':') && !hostname.starts_with('[') {
+        format!("[{}]:{}", hostname, port)
+    } else {
+        format!("{}:{}", hostname, port)
     }
 }
 
 pub fn exists(alias: &str) -> bool {
-    let mut path = std::path::PathBuf::new();
-    path.push(dirs::home_dir().expect("Failed to get home directory"));
-    path.push(HERMOD_BASE_DIR);
-    path.push(HOST_DIR);
-    path.push(alias);
-    path.as_path().exists()
+    host_path(alias).exists()
 }
 
+/// Load a host, asking for the passphrase if the private key is protected by one
 pub fn load_host(alias: &str) -> Result<Host, HermodError> {
+    load_host_with_passphrase(alias).map(|(host, _)| host)
+}
+
+/// Load a host like `load_host`, also returns the passphrase of the private key if it has one
+pub fn load_host_with_passphrase(
+    alias: &str,
+) -> Result<(Host, Option<Zeroizing<String>>), HermodError> {
+    let mut host = read_host(alias)?;
+    let mut passphrase = None;
+    if let Some(ref encrypted) = host.encrypted_private_key {
+        let prompt = format!("Enter passphrase for {}: ", alias);
+        let secret = passphrase::read(&prompt, None)?;
+        host.private_key = passphrase::decrypt(encrypted, &secret)?;
+        passphrase = Some(secret);
+    }
+    Ok((host, passphrase))
+}
+
+/// Read a host file without decrypting the private key
+pub fn read_host(alias: &str) -> Result<Host, HermodError> {
+    let path = host_path(alias);
+    // The host file holds the private key of the client
+    permissions::check_private_file(&path)?;
+    let content = Zeroizing::new(std::fs::read_to_string(&path)?);
+    from_content(alias, &content, &path)
+}
+
+fn host_path(alias: &str) -> PathBuf {
     let mut path = PathBuf::new();
     path.push(dirs::home_dir().expect("Failed to get home directory"));
     path.push(HERMOD_BASE_DIR);
     path.push(HOST_DIR);
     path.push(alias);
+    path
+}
 
-    let mut host = Host::with_alias(alias);
+/// Parse the content of a host file, host files in the legacy format are rewritten in the
+/// current format
+fn from_content(alias: &str, content: &str, path: &Path) -> Result<Host, HermodError> {
+    let (host, legacy) = parse(alias, content).map_err(|err| {
+        let msg = format!("{}:{}: {}", path.display(), err.line, err.message);
+        HermodError::new(HermodErrorKind::HostFile(msg))
+    })?;
 
-    let file = std::fs::File::open(path)?;
-    let buf_reader = std::io::BufReader::new(file);
-    for line in buf_reader.lines() {
-        let line = line?;
+    if legacy {
+        host.write_to_file()?;
+    }
+    Ok(host)
+}
 
-        let parts: Vec<&str> = line.split_whitespace().collect();
+/// Parse a host file, returns the host and whether the file used the legacy format
+fn parse(alias: &str, content: &str) -> Result<(Host, bool), ParseError> {
+    let is_legacy = content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .and_then(|line| line.split_whitespace().next())
+        .is_some_and(|key| key.ends_with(':'));
+
+    if is_legacy {
+        parse_legacy(alias, content).map(|host| (host, true))
+    } else {
+        parse_versioned(alias, content).map(|host| (host, false))
+    }
+}
 
-        host = match parts[0] {
-            "PublicKey:" => host.set_public_key(&base64::decode(parts[1])?),
-            "PrivateKey:" => host.set_private_key(&base64::decode(parts[1])?),
-            "Hostname:" => host.set_hostname(parts[1]),
-            "IdToken:" => host.set_id_token(parts[1]),
-            "ServerKey:" => host.set_server_key(&base64::decode(parts[1])?),
-            _ => host,
+fn parse_versioned(alias: &str, content: &str) -> Result<Host, ParseError> {
+    let file: HostFile = toml::from_str(content).map_err(|e| {
+        let line = e.line_col().map_or(0, |(line, _)| line + 1);
+        // The toml error repeats the position, only keep the description
+        let message = e.to_string();
+        let message = match message.find(" at line ") {
+            Some(i) => message[..i].to_owned(),
+            None => message,
         };
+        ParseError { line, message }
+    })?;
+
+    if file.version != HOST_FILE_VERSION {
+        return Err(ParseError {
+            line: line_of(content, "version"),
+            message: format!("unsupported version {}", file.version),
+        });
+    }
+
+    let key = |field: &str, value: &str| {
+        decode_key(value).map_err(|message| ParseError {
+            line: line_of(content, field),
+            message: format!("{}: {}", field, message),
+        })
+    };
+
+    validate_id_token(&file.id_token).map_err(|message| ParseError {
+        line: line_of(content, "id_token"),
+        message,
+    })?;
+
+    let mut host = Host::with_alias(alias)
+        .set_hostname(&file.hostname)
+        .set_port(file.port)
+        .set_id_token(&file.id_token)
+        .set_public_key(&key("public_key", &file.public_key)?)
+        .set_server_key(&key("server_key", &file.server_key)?)
+        .set_certificate(file.certificate.clone());
+
+    // An encrypted private key is kept as is until it is unlocked
+    if passphrase::is_encrypted(&file.private_key) {
+        passphrase::check(&file.private_key).map_err(|e| ParseError {
+            line: line_of(content, "private_key"),
+            message: format!("private_key: {}", e),
+        })?;
+        host.encrypted_private_key = Some(file.private_key.clone());
+    } else {
+        let private_key = Zeroizing::new(key("private_key", &file.private_key)?);
+        host = host.set_private_key(&private_key);
+    }
+    if let Some(ref psk) = file.psk {
+        let psk = Zeroizing::new(key("psk", psk)?);
+        if psk.len() != PSK_LEN {
+            return Err(ParseError {
+                line: line_of(content, "psk"),
+                message: String::from("psk: missing key"),
+            });
+        }
+        host = host.set_psk(Some(&psk));
     }
     Ok(host)
 }
 
-pub async fn load_host_async(alias: &str) -> Result<Host, HermodError> {
-    let mut path = PathBuf::new();
-    path.push(dirs::home_dir().expect("Failed to get home directory"));
-    path.push(HERMOD_BASE_DIR);
-    path.push(HOST_DIR);
-    path.push(alias);
-
+/// Parse the original `Key: value` format
+fn parse_legacy(alias: &str, content: &str) -> Result<Host, ParseError> {
     let mut host = Host::with_alias(alias);
 
-    let file = File::open(path).await?;
-    let mut lines = BufReader::new(file).lines();
-    while let Some(line) = lines.next().await {
-        let line = line?;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |message: String| ParseError {
+            line: i + 1,
+            message,
+        };
 
-        let parts: Vec<&str> = line.split_whitespace().collect();
+        let mut parts = line.split_whitespace();
+        let key = parts.next().unwrap_or_default();
+        // Keys written before a server key was known have an empty value
+        let value = parts.next().unwrap_or_default();
+        if parts.next().is_some() {
+            return Err(error(format!("unexpected data after the value of {}", key)));
+        }
 
-        host = match parts[0] {
-            "PublicKey:" => host.set_public_key(&base64::decode(parts[1])?),
-            "PrivateKey:" => host.set_private_key(&base64::decode(parts[1])?),
-            "Hostname:" => host.set_hostname(parts[1]),
-            "IdToken:" => host.set_id_token(parts[1]),
-            "ServerKey:" => host.set_server_key(&base64::decode(parts[1])?),
-            _ => host,
+        host = match key {
+            "PublicKey:" => host.set_public_key(&decode_key(value).map_err(error)?),
+            "PrivateKey:" => {
+                host.set_private_key(&Zeroizing::new(decode_key(value).map_err(error)?))
+            }
+            "ServerKey:" => host.set_server_key(&decode_key(value).map_err(error)?),
+            "IdToken:" => {
+                validate_id_token(value).map_err(error)?;
+                host.set_id_token(value)
+            }
+            "Hostname:" => {
+                let (hostname, port) = split_port(value).map_err(error)?;
+                host.set_hostname(hostname).set_port(port)
+            }
+            _ => return Err(error(format!("unknown key '{}'", key))),
         };
     }
     Ok(host)
 }
+
+/// Split `host:port`, the port is optional
+fn split_port(value: &str) -> Result<(&str, u16), String> {
+    match value.rsplit_once(':') {
+        // A bare IPv6 address without brackets has no port
+        Some((host, _)) if host.contains(':') && !host.ends_with(']') => Ok((value, HERMOD_PORT)),
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("invalid port '{}'", port))?;
+            Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+        }
+        None => Ok((value, HERMOD_PORT)),
+    }
+}
+
+fn decode_key(value: &str) -> Result<Vec<u8>, String> {
+    let key = base64::decode(value).map_err(|e| format!("invalid key: {}", e))?;
+    match key.len() {
+        0 | 32 => Ok(key),
+        len => Err(format!("invalid key length {}, expected 32 bytes", len)),
+    }
+}
+
+fn validate_id_token(token: &str) -> Result<(), String> {
+    if token.is_empty() || token.len() == ID_TOKEN_B64LEN as usize {
+        Ok(())
+    } else {
+        Err(format!("invalid id token '{}'", token))
+    }
+}
+
+/// Find the line a field is defined on, used to point at values that fail validation
+fn line_of(content: &str, field: &str) -> usize {
+    content
+        .lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(field)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        })
+        .map_or(0, |i| i + 1)
+}
+
+fn default_port() -> u16 {
+    HERMOD_PORT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn parse_legacy_format() {
+        let content = format!(
+            "Hostname: 127.0.0.1:4445\nPublicKey: {}\nPrivateKey: {}\n\nIdToken: AAAAAAAAAAA=\nServerKey: \n",
+            KEY, KEY
+        );
+        let (host, legacy) = parse("local", &content).unwrap();
+        assert!(legacy);
+        assert_eq!(host.hostname(), "127.0.0.1");
+        assert_eq!(host.port(), 4445);
+        assert_eq!(host.public_key().len(), 32);
+        assert!(host.server_key().is_empty());
+    }
+
+    #[test]
+    fn parse_versioned_format() {
+        let content = format!(
+            "version = 1\nhostname = \"::1\"\nid_token = \"AAAAAAAAAAA=\"\npublic_key = \"{}\"\nprivate_key = \"{}\"\nserver_key = \"{}\"\n",
+            KEY, KEY, KEY
+        );
+        let (host, legacy) = parse("local", &content).unwrap();
+        assert!(!legacy);
+        assert_eq!(host.address(), "[::1]:4444");
+        assert_eq!(host.server_key().len(), 32);
+    }
+
+    #[test]
+    fn report_line_of_invalid_value() {
+        let legacy = "Hostname: example.com\nPublicKey: not-base64\n";
+        assert_eq!(parse("local", legacy).err().unwrap().line, 2);
+
+        let versioned = format!(
+            "version = 1\nhostname = \"example.com\"\nid_token = \"\"\npublic_key = \"{}\"\nprivate_key = \"AAAA\"\n",
+            KEY
+        );
+        let err = parse("local", &versioned).err().unwrap();
+        assert_eq!(err.line, 5);
+        assert!(err.message.starts_with("private_key"));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::error::{HermodError, HermodErrorKind};
+use crate::secret::SecretKey;
+
+use std::convert::TryFrom;
+
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, EncodedSizeUser, KemCore, MlKem768};
+
+use rand_core::OsRng;
+
+use sha2::{Digest, Sha256};
+
+use zeroize::Zeroizing;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// The client side of the ML-KEM key exchange of a hybrid handshake.
+/// The shared secret is mixed into the Noise handshake in place of a pre-shared key, so that
+/// a recorded session stays confidential even if X25519 is broken later.
+pub struct KemKeyPair {
+    decapsulation_key: DecapsulationKey,
+    encapsulation_key: Vec<u8>,
+}
+
+impl KemKeyPair {
+    /// A new key pair, used for a single handshake
+    pub fn generate() -> Self {
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut OsRng);
+        KemKeyPair {
+            decapsulation_key,
+            encapsulation_key: encapsulation_key.as_bytes().to_vec(),
+        }
+    }
+
+    /// Sent to the server, which encapsulates a shared secret to it
+    pub fn encapsulation_key(&self) -> &[u8] {
+        &self.encapsulation_key
+    }
+
+    /// The shared secret in the ciphertext from the server
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, HermodError> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| error("invalid length of the ciphertext"))?;
+        let shared = self
+            .decapsulation_key
+            .decapsulate(&ciphertext)
+            .map_err(|_| error("failed to decapsulate the shared secret"))?;
+        Ok(Zeroizing::new(shared.to_vec()))
+    }
+}
+
+/// Encapsulate a shared secret to the key of the client, returns the ciphertext for the client
+/// and the shared secret
+pub fn encapsulate(encapsulation_key: &[u8]) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>), HermodError> {
+    let key = ml_kem::Encoded::<EncapsulationKey>::try_from(encapsulation_key)
+        .map_err(|_| error("invalid length of the encapsulation key"))?;
+    let (ciphertext, shared) = EncapsulationKey::from_bytes(&key)
+        .encapsulate(&mut OsRng)
+        .map_err(|_| error("failed to encapsulate a shared secret"))?;
+    Ok((ciphertext.to_vec(), Zeroizing::new(shared.to_vec())))
+}
+
+/// The key mixed into the handshake, from the shared secret and the pre-shared key of the
+/// client if it has one
+pub fn handshake_psk(shared: &[u8], psk: Option<&[u8]>) -> SecretKey {
+    let mut hasher = Sha256::new();
+    hasher.input(b"hermod-hybrid");
+    hasher.input(shared);
+    hasher.input(psk.unwrap_or_default());
+    SecretKey::new(&hasher.result())
+}
+
+fn error(msg: &str) -> HermodError {
+    HermodError::new(HermodErrorKind::Hybrid(msg.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agree_on_handshake_psk() {
+        let keys = KemKeyPair::generate();
+        let (ciphertext, shared) = encapsulate(keys.encapsulation_key()).unwrap();
+        let decapsulated = keys.decapsulate(&ciphertext).unwrap();
+        assert_eq!(shared, decapsulated);
+
+        let psk = [7u8; 32];
+        assert_eq!(
+            handshake_psk(&shared, Some(&psk)).as_bytes(),
+            handshake_psk(&decapsulated, Some(&psk)).as_bytes()
+        );
+        assert_ne!(
+            handshake_psk(&shared, None).as_bytes(),
+            handshake_psk(&shared, Some(&psk)).as_bytes()
+        );
+        assert!(keys.decapsulate(&ciphertext[1..]).is_err());
+    }
+}
@@ -1,32 +1,146 @@
 use crate::consts::*;
 use crate::error::{HermodError, HermodErrorKind};
+use crate::permissions;
+use crate::secret::SecretKey;
 
 use std::collections::HashMap;
 use std::fmt;
-use std::fs::File;
+use std::fs;
 use std::io::prelude::*;
-use std::io::{self, BufReader, BufWriter};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::MutexGuard;
 
-use async_std::prelude::*;
 use async_std::sync::Mutex;
 
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, SecondsFormat, TimeZone, Utc};
+
 use lazy_static::lazy_static;
 
+use serde::{Deserialize, Serialize};
+
+use zeroize::Zeroizing;
+
 lazy_static! {
     pub static ref KNOWN_CLIENTS: Mutex<HashMap<String, Identity>> =
         Mutex::new(Identity::load_clients());
+    static ref CLIENTS_FILES: std::sync::Mutex<()> = std::sync::Mutex::new(());
 }
 
-#[derive(Debug)]
+/// Version of the authorized_clients format written by `write_clients`
+const CLIENTS_FILE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone)]
 pub struct Identity {
     pub id_token: String,
     pub client_key: Vec<u8>,
+    pub label: String,
+    pub created: Option<DateTime<Utc>>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: Option<DateTime<Utc>>,
+    pub access_hours: Vec<AccessHours>,
+    /// Key mixed into the handshake, a client with one must use it
+    pub psk: Option<SecretKey>,
+    /// Admitted by a certificate instead of being in authorized_clients
+    pub certified: bool,
+}
+
+/// Hours of the day a client may connect in, in the local time of the server.
+/// A window that ends before it starts spans midnight: `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+/// On disk representation of the authorized clients
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientsFile {
+    version: u32,
+    #[serde(default, rename = "client")]
+    clients: Vec<ClientEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientEntry {
+    id_token: String,
+    public_key: String,
+    #[serde(default)]
+    label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    not_before: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    not_after: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    access_hours: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    psk: Option<String>,
 }
 
 impl fmt::Display for Identity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}, {}", self.id_token, base64::encode(&self.client_key))
+        let created = match self.created {
+            Some(created) => created.with_timezone(&Local).format("%Y-%m-%d").to_string(),
+            None => String::from("-"),
+        };
+        let label = if self.label.is_empty() {
+            "-"
+        } else {
+            &self.label
+        };
+        write!(
+            f,
+            "{} {:16} {:10} {:13} {}",
+            self.id_token,
+            label,
+            created,
+            self.status(Utc::now()),
+            base64::encode(&self.client_key)
+        )
+    }
+}
+
+impl fmt::Display for AccessHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl FromStr for AccessHours {
+    type Err = String;
+
+    fn from_str(hours: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid access hours '{}', expected HH:MM-HH:MM", hours);
+        let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+        let time =
+            |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        let hours = AccessHours {
+            start: time(start)?,
+            end: time(end)?,
+        };
+        if hours.start == hours.end {
+            return Err(format!("the access hours '{}' are empty", hours));
+        }
+        Ok(hours)
+    }
+}
+
+impl AccessHours {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
     }
 }
 
@@ -35,9 +149,61 @@ impl Identity {
         Identity {
             id_token,
             client_key,
+            label: String::new(),
+            created: Some(Utc::now()),
+            not_before: None,
+            not_after: None,
+            access_hours: Vec::new(),
+            psk: None,
+            certified: false,
         }
     }
 
+    /// Check that the client may connect at `now`, within its validity period and, if it is
+    /// limited to access hours, within one of them
+    pub fn check_access(&self, now: DateTime<Utc>) -> Result<(), HermodError> {
+        let time = now.with_timezone(&Local).time();
+        let reason = if self.is_expired(now) {
+            "has expired"
+        } else if self.is_not_yet_valid(now) {
+            "is not valid yet"
+        } else if !self.access_hours.is_empty()
+            && !self.access_hours.iter().any(|h| h.contains(time))
+        {
+            "is outside its access hours"
+        } else {
+            return Ok(());
+        };
+        Err(HermodError::new(HermodErrorKind::AccessDenied(format!(
+            "the access of {} {}",
+            self.id_token, reason
+        ))))
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.not_after.is_some_and(|not_after| not_after <= now)
+    }
+
+    pub fn is_not_yet_valid(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.is_some_and(|not_before| now < not_before)
+    }
+
+    /// The validity of the client as shown when listing clients
+    fn status(&self, now: DateTime<Utc>) -> &'static str {
+        if self.is_expired(now) {
+            "expired"
+        } else if self.is_not_yet_valid(now) {
+            "not yet valid"
+        } else {
+            "active"
+        }
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = label.to_owned();
+        self
+    }
+
     pub fn get_public_key(&self) -> &[u8] {
         &self.client_key
     }
@@ -47,72 +213,660 @@ impl Identity {
     }
 
     pub fn load_clients() -> HashMap<String, Identity> {
-        let mut path = PathBuf::new();
-        path.push(dirs::home_dir().unwrap());
-        path.push(HERMOD_BASE_DIR);
-        path.push("authorized_clients");
-
-        let file = File::open(path).expect("Failed to open authorized_clients file");
-        let lines = BufReader::new(file).lines();
-
-        let mut clients = HashMap::new();
-
-        for line in lines {
-            let line = line.expect("Error reading authorized_clients file content");
-            let line_content: Vec<&str> = line.split(':').collect();
-
-            let id_token = line_content[0];
-            let client_key = base64::decode(line_content[1].as_bytes()).unwrap();
-
-            clients.insert(
-                id_token.to_string(),
-                Identity {
-                    id_token: id_token.to_string(),
-                    client_key,
-                },
-            );
-        }
+        let clients = migrate_clients().unwrap_or_else(|e| panic!("{}", e));
         clients
+            .into_iter()
+            .map(|id| (id.id_token.clone(), id))
+            .collect()
     }
 }
 
-pub async fn write_to_file(id: &Identity) -> Result<(), HermodError> {
+/// Replace the known clients with the content of the authorized_clients file.
+/// Returns the id tokens that are no longer authorized, either removed or given a new key.
+pub async fn reload_clients() -> Result<Vec<String>, HermodError> {
+    let clients = async_std::task::spawn_blocking(read_clients).await?;
+
+    let mut known = KNOWN_CLIENTS.lock().await;
+    let revoked = known
+        .values()
+        .filter(|old| {
+            clients
+                .iter()
+                .all(|new| new.id_token != old.id_token || new.client_key != old.client_key)
+        })
+        .map(|old| old.id_token.clone())
+        .collect();
+    *known = clients
+        .into_iter()
+        .map(|id| (id.id_token.clone(), id))
+        .collect();
+    Ok(revoked)
+}
+
+/// The id token of the known client with the public key `key`, clients that hide their id token
+/// are identified by their key
+pub async fn find_by_key(key: &[u8]) -> Option<String> {
+    let known = KNOWN_CLIENTS.lock().await;
+    known
+        .values()
+        .find(|id| id.client_key == key)
+        .map(|id| id.id_token.clone())
+}
+
+/// Those of `ids` that are known clients no longer allowed to connect at `now`
+pub async fn without_access(ids: &[String], now: DateTime<Utc>) -> Vec<String> {
+    let clients = KNOWN_CLIENTS.lock().await;
+    ids.iter()
+        .filter(|id| {
+            clients
+                .get(id.as_str())
+                .is_some_and(|client| client.check_access(now).is_err())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Path of the authorized_clients file, watched by the server for changes
+pub fn clients_path() -> PathBuf {
     let mut path = PathBuf::new();
     path.push(dirs::home_dir().expect("Failed to get home directory path"));
     path.push(HERMOD_BASE_DIR);
-    path.push("authorized_clients");
-
-    let mut file = async_std::fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(path)
-        .await
-        .expect("Failed to open authorized_clients file");
-
-    file.write_all(format!("{}:{}\n", id.id_token, base64::encode(&id.client_key)).as_bytes())
-        .await
-        .map_err(|_| HermodError::new(HermodErrorKind::Other))?;
-    file.flush().await?;
+    path.push(HERMOD_CLIENTS_FILE);
+    path
+}
+
+/// Path of the pending_clients file, clients that shared their key and await approval
+pub fn pending_path() -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(dirs::home_dir().expect("Failed to get home directory path"));
+    path.push(HERMOD_BASE_DIR);
+    path.push(HERMOD_PENDING_CLIENTS_FILE);
+    path
+}
+
+/// Exclusive access to authorized_clients and pending_clients, held from reading the clients
+/// until the changes are written. Taken by the threads of the server as well as the command
+/// line, which share an advisory lock on a file next to the clients.
+struct ClientsLock {
+    _file: fs::File,
+    _guard: MutexGuard<'static, ()>,
+}
+
+fn lock_clients() -> Result<ClientsLock, HermodError> {
+    let guard = CLIENTS_FILES.lock().unwrap_or_else(|e| e.into_inner());
+    let path = clients_path().with_extension("lock");
+    if let Some(dir) = path.parent() {
+        permissions::create_private_dir(dir)?;
+    }
+    Ok(ClientsLock {
+        _file: permissions::lock_file(&path)?,
+        _guard: guard,
+    })
+}
+
+/// Read the authorized clients in the order they were added, a missing file has no clients
+pub fn read_clients() -> Result<Vec<Identity>, HermodError> {
+    read_clients_from(&clients_path()).map(|(clients, _)| clients)
+}
+
+/// Read the authorized clients and rewrite a file in the legacy `id:key` format in the current
+/// format. The clients are locked, so that a change made at the same time is not lost.
+fn migrate_clients() -> Result<Vec<Identity>, HermodError> {
+    let _lock = lock_clients()?;
+    let (clients, legacy) = read_clients_from(&clients_path())?;
+    if legacy {
+        write_clients(&clients)?;
+    }
+    Ok(clients)
+}
+
+/// Read the clients awaiting approval, stored in the same format as the authorized clients
+pub fn read_pending() -> Result<Vec<Identity>, HermodError> {
+    read_clients_from(&pending_path()).map(|(clients, _)| clients)
+}
+
+/// The clients in `path` and whether the file is in the legacy format
+fn read_clients_from(path: &Path) -> Result<(Vec<Identity>, bool), HermodError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+        Err(e) => return Err(HermodError::new(HermodErrorKind::ReadAuthorizedClients(e))),
+    };
+    permissions::warn_if_writable(path);
+
+    let (clients, legacy) = parse(&content).map_err(|(line, msg)| {
+        let msg = match line {
+            0 => format!("{}: {}", path.display(), msg),
+            line => format!("{}:{}: {}", path.display(), line, msg),
+        };
+        HermodError::new(HermodErrorKind::ClientsFile(msg))
+    })?;
+    Ok((clients, legacy))
+}
+
+/// Replace the authorized clients, the file is written to a temporary file first and moved in
+/// place so that a failed write never leaves a partial file behind
+pub fn write_clients(clients: &[Identity]) -> Result<(), HermodError> {
+    write_clients_to(&clients_path(), clients)
+}
+
+fn write_clients_to(path: &Path, clients: &[Identity]) -> Result<(), HermodError> {
+    let file = ClientsFile {
+        version: CLIENTS_FILE_VERSION,
+        clients: clients
+            .iter()
+            .map(|id| ClientEntry {
+                id_token: id.id_token.clone(),
+                public_key: base64::encode(&id.client_key),
+                label: id.label.clone(),
+                created: id.created.map(format_time),
+                not_before: id.not_before.map(format_time),
+                not_after: id.not_after.map(format_time),
+                access_hours: id.access_hours.iter().map(ToString::to_string).collect(),
+                psk: id.psk.as_ref().map(base64::encode),
+            })
+            .collect(),
+    };
+    let content = toml::to_string(&file).map_err(|e| {
+        HermodError::new(HermodErrorKind::ClientsFile(format!(
+            "Failed to encode the clients: {}",
+            e
+        )))
+    })?;
+
+    if let Some(dir) = path.parent() {
+        permissions::create_private_dir(dir)?;
+    }
+    let tmp_path = permissions::temporary_path(path);
+    let mut tmp = permissions::create_private_file(&tmp_path)?;
+    tmp.write_all(content.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
     Ok(())
 }
 
+/// Store a new client, used when a client shares its key with the server
+pub async fn write_to_file(id: &Identity) -> Result<(), HermodError> {
+    let id = id.clone();
+    async_std::task::spawn_blocking(move || add_client(id)).await
+}
+
+/// Add a client, the id token, key and label must not already be in use
+pub fn add_client(id: Identity) -> Result<(), HermodError> {
+    let _lock = lock_clients()?;
+    add_locked(id)
+}
+
+fn add_locked(id: Identity) -> Result<(), HermodError> {
+    validate(&id.id_token, &id.client_key)
+        .map_err(|msg| HermodError::new(HermodErrorKind::InvalidClient(msg)))?;
+    let mut clients = read_clients()?;
+    if clients.iter().any(|client| client.id_token == id.id_token) {
+        return Err(HermodError::new(HermodErrorKind::ClientExists(id.id_token)));
+    }
+    check_key(&clients, &id.id_token, &id.client_key)?;
+    check_label(&clients, &id.label)?;
+    clients.push(id);
+    write_clients(&clients)
+}
+
+/// Queue a client until it is approved, used when a client shares its key with a server that
+/// requires approval
+pub fn add_pending(id: Identity) -> Result<(), HermodError> {
+    validate(&id.id_token, &id.client_key)
+        .map_err(|msg| HermodError::new(HermodErrorKind::InvalidClient(msg)))?;
+    let _lock = lock_clients()?;
+    let mut pending = read_pending()?;
+    // Anyone that can reach the server can ask to be enrolled, keep the queue bounded
+    if pending.len() >= HERMOD_MAX_PENDING_CLIENTS {
+        return Err(HermodError::new(HermodErrorKind::InvalidClient(
+            String::from("too many clients are awaiting approval"),
+        )));
+    }
+    let exists = |clients: &[Identity]| clients.iter().any(|c| c.id_token == id.id_token);
+    if exists(&pending) || exists(&read_clients()?) {
+        return Err(HermodError::new(HermodErrorKind::ClientExists(id.id_token)));
+    }
+    pending.push(id);
+    write_clients_to(&pending_path(), &pending)
+}
+
+/// Authorize the pending client with the id token or label `name`, optionally with a new label
+pub fn approve_pending(name: &str, label: Option<&str>) -> Result<Identity, HermodError> {
+    let _lock = lock_clients()?;
+    let mut pending = read_pending()?;
+    let index = find_client(&pending, name)?;
+    let mut id = pending.remove(index);
+    if let Some(label) = label {
+        id.label = label.to_owned();
+    }
+    add_locked(id.clone())?;
+    write_clients_to(&pending_path(), &pending)?;
+    Ok(id)
+}
+
+/// Remove the pending client with the id token or label `name` without authorizing it
+pub fn reject_pending(name: &str) -> Result<Identity, HermodError> {
+    let _lock = lock_clients()?;
+    let mut pending = read_pending()?;
+    let index = find_client(&pending, name)?;
+    let id = pending.remove(index);
+    write_clients_to(&pending_path(), &pending)?;
+    Ok(id)
+}
+
+/// Remove the client with the id token or label `name`
+pub fn remove_client(name: &str) -> Result<Identity, HermodError> {
+    let _lock = lock_clients()?;
+    let mut clients = read_clients()?;
+    let index = find_client(&clients, name)?;
+    let id = clients.remove(index);
+    write_clients(&clients)?;
+    Ok(id)
+}
+
+/// Give a client a new key, used when a client rotates its key over an authenticated session.
+/// `session_key` is the static key the session was authenticated with, it must be the key of
+/// the client being replaced.
+/// The known clients stay locked while the file is written, so that the reload caused by the
+/// change does not take the new key for a revocation of the client.
+pub async fn replace_key(
+    peer: &Identity,
+    session_key: &[u8],
+    client_key: Vec<u8>,
+) -> Result<(), HermodError> {
+    let mut known = KNOWN_CLIENTS.lock().await;
+    let peer = peer.clone();
+    let session_key = session_key.to_vec();
+    let id = async_std::task::spawn_blocking(move || {
+        let _lock = lock_clients()?;
+        let mut clients = read_clients()?;
+        let index = rotate(&mut clients, &peer, &session_key, client_key)?;
+        write_clients(&clients)?;
+        Ok::<_, HermodError>(clients.swap_remove(index))
+    })
+    .await?;
+    known.insert(id.id_token.clone(), id);
+    Ok(())
+}
+
+/// Replace the key of `peer` among `clients` by `client_key`, returns the index of the client.
+/// A certified client picked its own id token, which may name another client, so only clients
+/// in authorized_clients that authenticated with their stored key may rotate it.
+fn rotate(
+    clients: &mut [Identity],
+    peer: &Identity,
+    session_key: &[u8],
+    client_key: Vec<u8>,
+) -> Result<usize, HermodError> {
+    if peer.certified {
+        return Err(HermodError::new(HermodErrorKind::InvalidClient(
+            String::from("a client admitted by a certificate can not rotate its key"),
+        )));
+    }
+    validate(&peer.id_token, &client_key)
+        .map_err(|msg| HermodError::new(HermodErrorKind::InvalidClient(msg)))?;
+    let index = clients
+        .iter()
+        .position(|id| id.id_token == peer.id_token)
+        .ok_or_else(|| HermodError::new(HermodErrorKind::UnknownClient(peer.id_token.clone())))?;
+    if clients[index].client_key != session_key {
+        return Err(HermodError::new(HermodErrorKind::InvalidClient(
+            String::from("the session was not authenticated with the key being replaced"),
+        )));
+    }
+    check_key(clients, &clients[index].id_token, &client_key)?;
+    clients[index].client_key = client_key;
+    Ok(index)
+}
+
+/// Give the client with the id token or label `name` a new label
+pub fn rename_client(name: &str, label: &str) -> Result<(), HermodError> {
+    let _lock = lock_clients()?;
+    let mut clients = read_clients()?;
+    let index = find_client(&clients, name)?;
+    if clients[index].label != label {
+        check_label(&clients, label)?;
+    }
+    clients[index].label = label.to_owned();
+    write_clients(&clients)
+}
+
+pub fn show_client(name: &str) -> Result<(), HermodError> {
+    let clients = read_clients()?;
+    let id = &clients[find_client(&clients, name)?];
+    println!("IdToken:   {}", id.id_token);
+    println!("Label:     {}", id.label);
+    match id.created {
+        Some(created) => println!("Created:   {}", created.with_timezone(&Local).to_rfc2822()),
+        None => println!("Created:   unknown"),
+    }
+    println!("PublicKey: {}", base64::encode(&id.client_key));
+    let local = |time: Option<DateTime<Utc>>| {
+        time.map_or(String::from("-"), |time| {
+            time.with_timezone(&Local).to_rfc2822()
+        })
+    };
+    println!("NotBefore: {}", local(id.not_before));
+    println!("NotAfter:  {}", local(id.not_after));
+    if !id.access_hours.is_empty() {
+        let hours: Vec<String> = id.access_hours.iter().map(ToString::to_string).collect();
+        println!("Hours:     {}", hours.join(", "));
+    }
+    println!("Status:    {}", id.status(Utc::now()));
+    println!("PSK:       {}", if id.psk.is_some() { "yes" } else { "no" });
+    Ok(())
+}
+
+/// Set or remove the pre-shared key of the client with the id token or label `name`
+pub fn set_psk(name: &str, psk: Option<SecretKey>) -> Result<Identity, HermodError> {
+    let _lock = lock_clients()?;
+    let mut clients = read_clients()?;
+    let index = find_client(&clients, name)?;
+    clients[index].psk = psk;
+    write_clients(&clients)?;
+    Ok(clients.swap_remove(index))
+}
+
+/// Limit when the client with the id token or label `name` may connect, replacing any
+/// previous limits
+pub fn limit_client(
+    name: &str,
+    not_before: Option<DateTime<Utc>>,
+    not_after: Option<DateTime<Utc>>,
+    access_hours: Vec<AccessHours>,
+) -> Result<(), HermodError> {
+    let _lock = lock_clients()?;
+    let mut clients = read_clients()?;
+    let index = find_client(&clients, name)?;
+    clients[index].not_before = not_before;
+    clients[index].not_after = not_after;
+    clients[index].access_hours = access_hours;
+    write_clients(&clients)
+}
+
+/// Parse a point in time given on the command line, either RFC 3339 or a date, which is taken
+/// as midnight in local time
+pub fn parse_time(time: &str) -> Result<DateTime<Utc>, HermodError> {
+    let invalid = || {
+        HermodError::new(HermodErrorKind::InvalidClient(format!(
+            "invalid time '{}', expected YYYY-MM-DD or RFC 3339",
+            time
+        )))
+    };
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(time, "%Y-%m-%d").map_err(|_| invalid())?;
+    Local
+        .from_local_datetime(&date.and_time(NaiveTime::MIN))
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(invalid)
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Find a client by its id token or its label
+fn find_client(clients: &[Identity], name: &str) -> Result<usize, HermodError> {
+    clients
+        .iter()
+        .position(|id| id.id_token == name)
+        .or_else(|| {
+            clients
+                .iter()
+                .position(|id| !id.label.is_empty() && id.label == name)
+        })
+        .ok_or_else(|| HermodError::new(HermodErrorKind::UnknownClient(name.to_owned())))
+}
+
+/// Clients are identified by their key, it can not be shared with another client
+fn check_key(clients: &[Identity], id_token: &str, key: &[u8]) -> Result<(), HermodError> {
+    if clients
+        .iter()
+        .any(|id| id.client_key == key && id.id_token != id_token)
+    {
+        return Err(HermodError::new(HermodErrorKind::InvalidClient(
+            String::from("the key is used by another client"),
+        )));
+    }
+    Ok(())
+}
+
+fn check_label(clients: &[Identity], label: &str) -> Result<(), HermodError> {
+    if !label.is_empty() && clients.iter().any(|id| id.label == label) {
+        return Err(HermodError::new(HermodErrorKind::ClientExists(
+            label.to_owned(),
+        )));
+    }
+    Ok(())
+}
+
+/// Parse the authorized clients, returns the clients and whether the legacy format was used.
+/// Errors carry the 1-based line they were found on.
+fn parse(content: &str) -> Result<(Vec<Identity>, bool), (usize, String)> {
+    let first = content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'));
+    match first {
+        None => Ok((Vec::new(), false)),
+        Some(line) if line.starts_with("version") || line.starts_with('[') => {
+            parse_versioned(content).map(|clients| (clients, false))
+        }
+        Some(_) => parse_legacy(content).map(|clients| (clients, true)),
+    }
+}
+
+fn parse_versioned(content: &str) -> Result<Vec<Identity>, (usize, String)> {
+    let file: ClientsFile = toml::from_str(content).map_err(|e| {
+        let line = e.line_col().map_or(0, |(line, _)| line + 1);
+        let message = e.to_string();
+        let message = match message.find(" at line ") {
+            Some(i) => message[..i].to_owned(),
+            None => message,
+        };
+        (line, message)
+    })?;
+
+    if file.version != CLIENTS_FILE_VERSION {
+        return Err((
+            line_of(content, "version"),
+            format!("unsupported version {}", file.version),
+        ));
+    }
+
+    let mut clients = Vec::with_capacity(file.clients.len());
+    for entry in file.clients {
+        let mut id = identity(&entry.id_token, &entry.public_key)
+            .map_err(|msg| (line_of(content, &entry.id_token), msg))?;
+        id.label = entry.label;
+        let time = |time: &Option<String>| match time {
+            Some(time) => DateTime::parse_from_rfc3339(time)
+                .map(|time| Some(time.with_timezone(&Utc)))
+                .map_err(|e| (line_of(content, time), format!("invalid date: {}", e))),
+            None => Ok(None),
+        };
+        id.created = time(&entry.created)?;
+        id.not_before = time(&entry.not_before)?;
+        id.not_after = time(&entry.not_after)?;
+        id.access_hours = entry
+            .access_hours
+            .iter()
+            .map(|hours| hours.parse().map_err(|e| (line_of(content, hours), e)))
+            .collect::<Result<_, _>>()?;
+        if let Some(ref psk) = entry.psk {
+            let key = match base64::decode(psk) {
+                Ok(key) if key.len() == PSK_LEN => key,
+                _ => return Err((line_of(content, psk), String::from("invalid psk"))),
+            };
+            id.psk = Some(SecretKey::new(&Zeroizing::new(key)));
+        }
+        clients.push(id);
+    }
+    Ok(clients)
+}
+
+/// Parse the original format, one `id:key` pair per line
+fn parse_legacy(content: &str) -> Result<Vec<Identity>, (usize, String)> {
+    let mut clients = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id_token, key) = line
+            .split_once(':')
+            .ok_or_else(|| (i + 1, String::from("expected 'id:key'")))?;
+        let mut id = identity(id_token, key).map_err(|msg| (i + 1, msg))?;
+        id.created = None;
+        clients.push(id);
+    }
+    Ok(clients)
+}
+
+fn identity(id_token: &str, public_key: &str) -> Result<Identity, String> {
+    let key = base64::decode(public_key).map_err(|e| format!("invalid public key: {}", e))?;
+    validate(id_token, &key)?;
+    Ok(Identity::new(id_token.to_owned(), key))
+}
+
+fn validate(id_token: &str, key: &[u8]) -> Result<(), String> {
+    match base64::decode(id_token) {
+        Ok(token) if token.len() == ID_TOKEN_LEN as usize => (),
+        _ => return Err(format!("invalid id token '{}'", id_token)),
+    }
+    if key.len() != 32 {
+        return Err(format!(
+            "invalid public key length {}, expected 32 bytes",
+            key.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Find the line a value is defined on, used to point at entries that fail validation.
+/// 0 if it is not found, an empty value can not be told apart from others.
+fn line_of(content: &str, value: &str) -> usize {
+    if value.is_empty() {
+        return 0;
+    }
+    content
+        .lines()
+        .position(|line| line.contains(value))
+        .map_or(0, |i| i + 1)
+}
+
 pub fn print_known_clients() {
-    async_std::task::block_on(async {
-        let num_clients = KNOWN_CLIENTS.lock().await.len();
+    print_clients(read_clients(), "known");
+}
+
+pub fn print_pending_clients() {
+    print_clients(read_pending(), "pending");
+}
 
-        if num_clients == 0 {
-            println!("No known clients found.");
+fn print_clients(clients: Result<Vec<Identity>, HermodError>, kind: &str) {
+    let clients = match clients {
+        Ok(clients) => clients,
+        Err(e) => {
+            eprintln!("{}", e);
             return;
         }
+    };
 
-        let mut writer = BufWriter::new(io::stdout());
+    if clients.is_empty() {
+        println!("No {} clients found.", kind);
+        return;
+    }
+
+    println!("Found {} {} client(s)", clients.len(), kind);
+    println!("TOKEN        LABEL            CREATED    STATUS        PUBLIC_KEY");
+    for client in clients {
+        println!("{}", client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
 
-        writer
-            .write_all(format!("Found {} known client(s)\n", num_clients).as_ref())
-            .unwrap();
-        writer.write_all("TOKEN PUBLIC_KEY\n".as_ref()).unwrap();
-        KNOWN_CLIENTS.lock().await.values().for_each(|v| {
-            writer.write_all(format!("{}\n", v).as_ref()).unwrap();
-        });
-    });
+    #[test]
+    fn parse_legacy_clients() {
+        let content = format!("AAAAAAAAAAA=:{}\n\nAQEBAQEBAQE=:{}\n", KEY, KEY);
+        let (clients, legacy) = parse(&content).unwrap();
+        assert!(legacy);
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[1].id_token, "AQEBAQEBAQE=");
+        assert!(clients[1].created.is_none());
+
+        assert_eq!(parse("AAAAAAAAAAA=\n").err().unwrap().0, 1);
+    }
+
+    #[test]
+    fn parse_versioned_clients() {
+        let content = format!(
+            "version = 1\n\n[[client]]\nid_token = \"AAAAAAAAAAA=\"\npublic_key = \"{}\"\nlabel = \"laptop\"\ncreated = \"2020-05-01T12:00:00Z\"\n\n[[client]]\nid_token = \"AQEBAQEB\"\npublic_key = \"{}\"\n",
+            KEY, KEY
+        );
+        let err = parse(&content).err().unwrap();
+        assert_eq!(err.0, 10);
+
+        let content = content.replace("AQEBAQEB\"", "AQEBAQEBAQE=\"");
+        let (clients, legacy) = parse(&content).unwrap();
+        assert!(!legacy);
+        assert_eq!(clients[0].label, "laptop");
+        assert!(clients[0].created.is_some());
+    }
+
+    #[test]
+    fn check_access_of_limited_client() {
+        let hours: AccessHours = "22:00-06:00".parse().unwrap();
+        assert!(hours.contains(NaiveTime::from_hms_opt(23, 30, 0).unwrap()));
+        assert!(hours.contains(NaiveTime::from_hms_opt(5, 59, 0).unwrap()));
+        assert!(!hours.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        assert!("08:00-08:00".parse::<AccessHours>().is_err());
+
+        let now = Utc::now();
+        let mut id = Identity::new(String::from("AAAAAAAAAAA="), base64::decode(KEY).unwrap());
+        id.not_after = Some(now + chrono::Duration::days(1));
+        assert!(id.check_access(now).is_ok());
+        assert!(id.check_access(now + chrono::Duration::days(2)).is_err());
+        assert_eq!(id.status(now + chrono::Duration::days(2)), "expired");
+    }
+
+    #[test]
+    fn rotate_only_the_session_key() {
+        let key = base64::decode(KEY).unwrap();
+        let mut clients = vec![Identity::new(String::from("AAAAAAAAAAA="), key.clone())];
+        let attacker_key = vec![7u8; 32];
+        let new_key = vec![9u8; 32];
+
+        let known = clients[0].clone();
+        assert!(rotate(&mut clients, &known, &attacker_key, new_key.clone()).is_err());
+        assert_eq!(clients[0].client_key, key);
+
+        assert_eq!(
+            rotate(&mut clients, &known, &key, new_key.clone()).unwrap(),
+            0
+        );
+        assert_eq!(clients[0].client_key, new_key);
+    }
+
+    #[test]
+    fn certified_clients_can_not_rotate_keys() {
+        let key = base64::decode(KEY).unwrap();
+        let mut clients = vec![Identity::new(String::from("AAAAAAAAAAA="), key.clone())];
+        let attacker_key = vec![7u8; 32];
+
+        // A certificate naming the id token of a client in authorized_clients
+        let mut certified = Identity::new(String::from("AAAAAAAAAAA="), attacker_key.clone());
+        certified.certified = true;
+        assert!(rotate(&mut clients, &certified, &attacker_key, vec![9u8; 32]).is_err());
+        assert!(rotate(&mut clients, &certified, &key, vec![9u8; 32]).is_err());
+        assert_eq!(clients[0].client_key, key);
+    }
 }
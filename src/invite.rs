@@ -0,0 +1,356 @@
+use crate::consts::*;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::permissions;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Duration, Local, SecondsFormat, Utc};
+
+use lazy_static::lazy_static;
+
+use rand::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use zeroize::{Zeroize, Zeroizing};
+
+lazy_static! {
+    /// Serializes updates of the invites file, so that a code can only be used once
+    static ref INVITES_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Version of the invites format written by `write_invites`
+const INVITES_FILE_VERSION: u32 = 1;
+
+/// Letters and digits that are hard to mistake for each other
+const PAIRING_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const PAIRING_WORD_LEN: usize = 4;
+
+/// A pending invitation, only the hash of the code is stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Invite {
+    hash: String,
+    expires: String,
+    #[serde(default)]
+    label: String,
+}
+
+/// A pending pairing, the code is kept since both sides need it for the key exchange
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pairing {
+    nameplate: u16,
+    code: String,
+    expires: String,
+    #[serde(default)]
+    label: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InvitesFile {
+    version: u32,
+    #[serde(default, rename = "invite", skip_serializing_if = "Vec::is_empty")]
+    invites: Vec<Invite>,
+    #[serde(default, rename = "pairing", skip_serializing_if = "Vec::is_empty")]
+    pairings: Vec<Pairing>,
+}
+
+/// A claimed pairing code and the label for the new client
+pub struct ClaimedPairing {
+    pub code: Zeroizing<String>,
+    pub label: String,
+}
+
+/// Create a new single use invitation code that is valid for `valid_for`.
+/// Clients enrolled with the code are given `label`.
+pub fn create(valid_for: Duration, label: &str) -> Result<String, HermodError> {
+    create_in(&invites_path(), valid_for, label)
+}
+
+fn create_in(path: &Path, valid_for: Duration, label: &str) -> Result<String, HermodError> {
+    let mut bytes = [0u8; INVITE_CODE_LEN * 3 / 4];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD);
+
+    let _lock = lock_invites(path)?;
+    let mut file = read_invites_from(path)?;
+    file.remove_expired();
+    file.invites.push(Invite {
+        hash: hash(&code),
+        expires: expiry(valid_for),
+        label: label.to_owned(),
+    });
+    write_invites_to(path, file)?;
+    Ok(code)
+}
+
+/// Use up an invitation code, returns the label for the new client.
+/// Fails if the code is unknown, has expired or has already been used.
+pub fn redeem(code: &str) -> Result<String, HermodError> {
+    redeem_in(&invites_path(), code)
+}
+
+fn redeem_in(path: &Path, code: &str) -> Result<String, HermodError> {
+    let _lock = lock_invites(path)?;
+    let mut file = read_invites_from(path)?;
+    let expired = file.remove_expired();
+
+    let hash = hash(code);
+    let position = file.invites.iter().position(|invite| invite.hash == hash);
+    // Expired invites are removed even if the code is rejected, the file is left alone if
+    // nothing changed
+    let invite = position.map(|i| file.invites.remove(i));
+    if expired || invite.is_some() {
+        write_invites_to(path, file)?;
+    }
+
+    invite
+        .map(|invite| invite.label)
+        .ok_or_else(|| HermodError::new(HermodErrorKind::InvalidInvite))
+}
+
+/// Create a new single use pairing code that is valid for `valid_for`.
+/// The code starts with a nameplate, the number the server looks the pairing up by, followed
+/// by the secret part: `7-k3mq-x8fp`
+pub fn create_pairing(valid_for: Duration, label: &str) -> Result<String, HermodError> {
+    let _lock = lock_invites(&invites_path())?;
+    let mut file = read_invites()?;
+    file.remove_expired();
+
+    // The lowest free nameplate keeps the codes short
+    let nameplate = (1..=u16::MAX)
+        .find(|n| file.pairings.iter().all(|pairing| pairing.nameplate != *n))
+        .expect("No free nameplate for the pairing");
+    let mut rng = rand::thread_rng();
+    let mut word = || -> String {
+        (0..PAIRING_WORD_LEN)
+            .map(|_| *PAIRING_ALPHABET.choose(&mut rng).unwrap() as char)
+            .collect()
+    };
+    let code = format!("{}-{}-{}", nameplate, word(), word());
+
+    file.pairings.push(Pairing {
+        nameplate,
+        code: code.clone(),
+        expires: expiry(valid_for),
+        label: label.to_owned(),
+    });
+    write_invites(file)?;
+    Ok(code)
+}
+
+/// Use up the pairing with the given nameplate, so that every code only gets a single guess.
+/// Fails if there is no such pairing or it has expired.
+pub fn claim_pairing(nameplate: u16) -> Result<ClaimedPairing, HermodError> {
+    let _lock = lock_invites(&invites_path())?;
+    let mut file = read_invites()?;
+    let expired = file.remove_expired();
+
+    let position = file
+        .pairings
+        .iter()
+        .position(|pairing| pairing.nameplate == nameplate);
+    let pairing = position.map(|i| file.pairings.remove(i));
+    if expired || pairing.is_some() {
+        write_invites(file)?;
+    }
+
+    pairing
+        .map(|mut pairing| ClaimedPairing {
+            code: Zeroizing::new(std::mem::take(&mut pairing.code)),
+            label: std::mem::take(&mut pairing.label),
+        })
+        .ok_or_else(|| HermodError::new(HermodErrorKind::InvalidInvite))
+}
+
+/// Normalize a pairing code typed in by the user, returns the nameplate and the code
+pub fn parse_pairing_code(code: &str) -> Option<(u16, String)> {
+    let code = code.trim().to_lowercase();
+    let nameplate = code.split('-').next()?.parse().ok()?;
+    Some((nameplate, code))
+}
+
+/// Print the invitations and pairings that have not been used or expired yet
+pub fn print_invites() -> Result<(), HermodError> {
+    let mut file = read_invites()?;
+    file.remove_expired();
+    println!(
+        "Found {} pending invitation(s)",
+        file.invites.len() + file.pairings.len()
+    );
+    for invite in &file.invites {
+        println!(
+            "expires {} label {}",
+            format_expiry(&invite.expires),
+            format_label(&invite.label)
+        );
+    }
+    for pairing in &file.pairings {
+        println!(
+            "expires {} label {} pairing {}",
+            format_expiry(&pairing.expires),
+            format_label(&pairing.label),
+            pairing.nameplate
+        );
+    }
+    Ok(())
+}
+
+fn invites_path() -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(dirs::home_dir().expect("Failed to get home directory path"));
+    path.push(HERMOD_BASE_DIR);
+    path.push(HERMOD_INVITES_FILE);
+    path
+}
+
+/// Exclusive access to the invites, held from reading the invites until the changes are written.
+/// The server uses up codes while the command line creates them, they share an advisory lock
+/// on a file next to the invites.
+struct InvitesLock {
+    _file: fs::File,
+    _guard: MutexGuard<'static, ()>,
+}
+
+fn lock_invites(path: &Path) -> Result<InvitesLock, HermodError> {
+    let guard = INVITES_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(dir) = path.parent() {
+        permissions::create_private_dir(dir)?;
+    }
+    Ok(InvitesLock {
+        _file: permissions::lock_file(&path.with_extension("lock"))?,
+        _guard: guard,
+    })
+}
+
+fn read_invites() -> Result<InvitesFile, HermodError> {
+    read_invites_from(&invites_path())
+}
+
+fn read_invites_from(path: &Path) -> Result<InvitesFile, HermodError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => Zeroizing::new(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(InvitesFile {
+                version: INVITES_FILE_VERSION,
+                ..Default::default()
+            })
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let file: InvitesFile = toml::from_str(&content).map_err(|e| {
+        HermodError::new(HermodErrorKind::Config(format!(
+            "{}: {}",
+            path.display(),
+            e
+        )))
+    })?;
+    if file.version != INVITES_FILE_VERSION {
+        let msg = format!("{}: unsupported version {}", path.display(), file.version);
+        return Err(HermodError::new(HermodErrorKind::Config(msg)));
+    }
+    Ok(file)
+}
+
+fn write_invites(file: InvitesFile) -> Result<(), HermodError> {
+    write_invites_to(&invites_path(), file)
+}
+
+fn write_invites_to(path: &Path, file: InvitesFile) -> Result<(), HermodError> {
+    let content = toml::to_string(&file).map_err(|e| {
+        HermodError::new(HermodErrorKind::Config(format!(
+            "Failed to encode the invites: {}",
+            e
+        )))
+    })?;
+    let content = Zeroizing::new(content);
+
+    if let Some(dir) = path.parent() {
+        permissions::create_private_dir(dir)?;
+    }
+    permissions::write_private_file(path, content.as_bytes())?;
+    Ok(())
+}
+
+fn hash(code: &str) -> String {
+    base64::encode(&Sha256::digest(code.trim().as_bytes()))
+}
+
+fn expiry(valid_for: Duration) -> String {
+    (Utc::now() + valid_for).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn parse_expiry(expires: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(expires)
+        .ok()
+        .map(|expires| expires.with_timezone(&Utc))
+}
+
+/// Invites with an unreadable expiry time are treated as expired
+fn is_expired(expires: &str) -> bool {
+    parse_expiry(expires).is_none_or(|expires| expires <= Utc::now())
+}
+
+fn format_expiry(expires: &str) -> String {
+    parse_expiry(expires)
+        .map(|expires| {
+            expires
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+fn format_label(label: &str) -> &str {
+    if label.is_empty() {
+        "-"
+    } else {
+        label
+    }
+}
+
+impl Drop for Pairing {
+    fn drop(&mut self) {
+        self.code.zeroize();
+    }
+}
+
+impl InvitesFile {
+    /// Remove the invites and pairings that have expired, returns whether there were any
+    fn remove_expired(&mut self) -> bool {
+        let count = self.invites.len() + self.pairings.len();
+        self.invites.retain(|invite| !is_expired(&invite.expires));
+        self.pairings
+            .retain(|pairing| !is_expired(&pairing.expires));
+        self.invites.len() + self.pairings.len() != count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redeem_invites_once() {
+        let dir = std::env::temp_dir().join(format!("hermod-invites-{}", std::process::id()));
+        let path = dir.join(HERMOD_INVITES_FILE);
+
+        let code = create_in(&path, Duration::hours(1), "laptop").unwrap();
+        assert!(redeem_in(&path, "unknown").is_err());
+        assert_eq!(redeem_in(&path, &code).unwrap(), "laptop");
+        assert!(redeem_in(&path, &code).is_err());
+
+        let expired = create_in(&path, Duration::seconds(-1), "").unwrap();
+        assert!(redeem_in(&path, &expired).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,19 +1,30 @@
+pub mod certificate;
 pub mod cli;
 pub mod client;
 pub mod config;
 pub mod consts;
+pub mod cookie;
 pub mod error;
+pub mod filter;
 pub mod genkey;
 pub mod hacl;
 pub mod host;
+pub mod hybrid;
 pub mod identity;
+pub mod invite;
 pub mod log;
 pub mod message;
 pub mod noise;
+pub mod passphrase;
 pub mod peer;
+pub mod permissions;
 pub mod request;
+pub mod rotation;
+pub mod secret;
 pub mod server;
 pub mod share_key;
+pub mod shell;
+pub mod suite;
 
 #[cfg(test)]
 mod tests {
@@ -1,17 +1,13 @@
-use crate::consts::*;
+use std::path::Path;
 
-use std::path::PathBuf;
+pub fn setup_logger(
+    stdout: bool,
+    level: log::LevelFilter,
+    log_path: &Path,
+) -> Result<(), fern::InitError> {
+    let mut base_config = fern::Dispatch::new().level(level);
 
-pub fn setup_logger(stdout: bool, verbosity: u64) -> Result<(), fern::InitError> {
-    let mut base_config = fern::Dispatch::new();
-
-    base_config = match verbosity {
-        0 => base_config.level(log::LevelFilter::Info),
-        1 => base_config.level(log::LevelFilter::Debug),
-        _ => base_config.level(log::LevelFilter::Trace),
-    };
-
-    base_config = base_config.chain(file_logger()?);
+    base_config = base_config.chain(file_logger(log_path)?);
     if stdout {
         base_config = base_config.chain(stdout_logger()?);
     }
@@ -21,15 +17,7 @@ pub fn setup_logger(stdout: bool, verbosity: u64) -> Result<(), fern::InitError>
     Ok(())
 }
 
-fn file_logger() -> Result<fern::Dispatch, fern::InitError> {
-    let log_path: PathBuf = [
-        dirs::home_dir().expect("Failed to get home_directory"),
-        HERMOD_BASE_DIR.into(),
-        HERMOD_LOG_FILE.into(),
-    ]
-    .iter()
-    .collect();
-
+fn file_logger(log_path: &Path) -> Result<fern::Dispatch, fern::InitError> {
     let cfg = fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
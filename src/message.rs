@@ -20,6 +20,15 @@ pub enum MessageType {
     ShareHost,
     EndOfResponse,
     Rekey,
+    PairInit,
+    PairResp,
+    Pending,
+    KeyRotation,
+    RotateKey,
+    IdentityInit,
+    RetrySuite,
+    CookieReply,
+    Cookie,
     Unknown,
 }
 
@@ -41,6 +50,15 @@ impl fmt::Display for MessageType {
             MessageType::ShareHost => write!(f, "ShareHost"),
             MessageType::EndOfResponse => write!(f, "EndOfResponse"),
             MessageType::Rekey => write!(f, "Rekey"),
+            MessageType::PairInit => write!(f, "PairInit"),
+            MessageType::PairResp => write!(f, "PairResp"),
+            MessageType::Pending => write!(f, "Pending"),
+            MessageType::KeyRotation => write!(f, "KeyRotation"),
+            MessageType::RotateKey => write!(f, "RotateKey"),
+            MessageType::IdentityInit => write!(f, "IdentityInit"),
+            MessageType::RetrySuite => write!(f, "RetrySuite"),
+            MessageType::CookieReply => write!(f, "CookieReply"),
+            MessageType::Cookie => write!(f, "Cookie"),
             MessageType::Unknown => write!(f, "Unknown"),
         }
     }
@@ -64,6 +82,15 @@ impl From<u8> for MessageType {
             13 => MessageType::ShareHost,
             14 => MessageType::EndOfResponse,
             15 => MessageType::EndOfResponse,
+            16 => MessageType::PairInit,
+            17 => MessageType::PairResp,
+            18 => MessageType::Pending,
+            19 => MessageType::KeyRotation,
+            20 => MessageType::RotateKey,
+            21 => MessageType::IdentityInit,
+            22 => MessageType::RetrySuite,
+            23 => MessageType::CookieReply,
+            24 => MessageType::Cookie,
             _ => MessageType::Unknown,
         }
     }
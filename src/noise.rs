@@ -1,15 +1,13 @@
 use crate::config::Config;
 use crate::consts::*;
-use crate::error::HermodError;
-use crate::hacl::HaclResolver;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::hybrid::{self, KemKeyPair};
 use crate::message::{Message, MessageType};
 use crate::peer::Peer;
+use crate::secret::SecretKey;
+use crate::suite::{self, Suite};
 
-use snow::{
-    self,
-    resolvers::{DefaultResolver, FallbackResolver},
-    Builder, HandshakeState, TransportState,
-};
+use snow::{self, params::NoiseParams, Builder, HandshakeState, TransportState};
 
 use log::info;
 
@@ -30,6 +28,19 @@ pub struct NoiseStream {
     stream: TcpStream,
     noise: TransportState,
     bytes_sent: usize,
+    previous_key: bool,
+    suite: Suite,
+    post_quantum: bool,
+}
+
+/// The answer of the server to the first handshake message of the client
+enum Answer {
+    /// The handshake is complete, and whether the server used its previous key
+    Complete(bool),
+    /// Start over with the suite preferred by the server
+    Retry(Suite),
+    /// The server is under load, start over on a new connection presenting the cookie
+    Cookie(Vec<u8>),
 }
 
 impl<'cfg> NoiseStream {
@@ -38,45 +49,117 @@ impl<'cfg> NoiseStream {
         config: &C,
         stream: &mut TcpStream,
     ) -> Result<Self, HermodError> {
-        let mut noise = Builder::with_resolver(
-            NOISE_PATTERN.parse()?,
-            Box::new(FallbackResolver::new(
-                Box::new(HaclResolver),
-                Box::new(DefaultResolver),
-            )),
-        )
-        .local_private_key(config.get_private_key())
-        .remote_public_key(peer.get_public_key())
-        .build_initiator()?;
-        client_handshake(stream, &mut noise, peer.get_id().as_bytes()).await?;
-
-        let noise = noise.into_transport_mode()?;
-
-        Ok(NoiseStream {
-            stream: stream.to_owned(),
-            noise,
-            bytes_sent: 0,
-        })
+        let suites = config.suites();
+        let mut suite = *suites
+            .first()
+            .ok_or_else(|| suite_error("no cipher suites are configured"))?;
+        let mut retried = false;
+        let mut cookie: Option<Vec<u8>> = None;
+        let mut presented_cookie = false;
+        loop {
+            if let Some(cookie) = cookie.take() {
+                let mut packet = vec![MessageType::Cookie as u8];
+                packet.extend(cookie);
+                stream.write_all(&packet).await?;
+                presented_cookie = true;
+            }
+            let kem = if config.post_quantum() {
+                Some(KemKeyPair::generate())
+            } else {
+                None
+            };
+            // The static key of the client is only sent encrypted, the server identifies the
+            // client by it
+            let pattern = if peer.get_psk().is_some() || kem.is_some() {
+                IDENTITY_HIDING_PSK_PATTERN
+            } else {
+                IDENTITY_HIDING_PATTERN
+            };
+            let mut offered = vec![suite.to_byte()];
+            for byte in suites.iter().map(|suite| suite.to_byte()) {
+                if !offered.contains(&byte) {
+                    offered.push(byte);
+                }
+            }
+            let mut flags = 0;
+            if peer.get_psk().is_some() {
+                flags |= HANDSHAKE_PSK;
+            }
+            if kem.is_some() {
+                flags |= HANDSHAKE_HYBRID;
+            }
+            // The suite the server asked for is the one the client starts over with
+            let retry = if retried { Some(suite) } else { None };
+            let kem_key = kem.as_ref().map(|kem| kem.encapsulation_key());
+            let mut noise = builder(suite.params(pattern))
+                .local_private_key(config.get_private_key())
+                .remote_public_key(peer.get_public_key())
+                .prologue(&prologue(flags, &offered, retry, kem_key))
+                .build_initiator()?;
+            let answer =
+                client_handshake(stream, &mut noise, flags, &offered, peer, kem.as_ref()).await?;
+            match answer {
+                Answer::Complete(previous_key) => {
+                    return Ok(NoiseStream {
+                        stream: stream.to_owned(),
+                        noise: noise.into_transport_mode()?,
+                        bytes_sent: 0,
+                        previous_key,
+                        suite,
+                        post_quantum: kem.is_some(),
+                    })
+                }
+                Answer::Retry(preferred) if !retried && suites.contains(&preferred) => {
+                    suite = preferred;
+                    retried = true;
+                }
+                Answer::Retry(_) => {
+                    return Err(suite_error(
+                        "the server asked for a suite that was not offered",
+                    ))
+                }
+                Answer::Cookie(_) if presented_cookie => {
+                    return Err(HermodError::new(HermodErrorKind::ServerBusy))
+                }
+                Answer::Cookie(reply) => {
+                    *stream = TcpStream::connect(stream.peer_addr()?).await?;
+                    cookie = Some(reply);
+                }
+            }
+        }
     }
 
+    /// Responder of the handshake of older clients, which send their id token ahead of it
     pub async fn new_responder<C: Config<'cfg>>(
         peer: &Peer,
         config: &C,
         stream: &mut TcpStream,
         message: &Message,
     ) -> Result<Self, HermodError> {
-        let mut noise = Builder::with_resolver(
-            NOISE_PATTERN.parse()?,
-            Box::new(FallbackResolver::new(
-                Box::new(HaclResolver),
-                Box::new(DefaultResolver),
-            )),
-        )
-        .local_private_key(config.get_private_key())
-        .remote_public_key(peer.get_public_key())
-        .build_responder()?;
+        if peer.get_psk().is_some() {
+            return Err(psk_required());
+        }
+        if config.post_quantum() {
+            return Err(post_quantum_required());
+        }
+        let responder = |private_key: &[u8]| -> Result<HandshakeState, HermodError> {
+            Ok(builder(NOISE_PATTERN.parse()?)
+                .local_private_key(private_key)
+                .remote_public_key(peer.get_public_key())
+                .build_responder()?)
+        };
+        let mut noise = responder(config.get_private_key())?;
 
-        server_handshake(stream, &mut noise, message).await?;
+        // Clients that have not learned about a rotated key yet still use the previous one
+        let mut previous_key = false;
+        if let Err(e) = noise.read_message(&message.get_payload()[12..], &mut []) {
+            let private_key = config.get_previous_private_key().ok_or(e)?;
+            noise = responder(private_key)?;
+            noise.read_message(&message.get_payload()[12..], &mut [])?;
+            previous_key = true;
+        }
+
+        server_handshake(stream, &mut noise, previous_key, None).await?;
 
         let noise = noise.into_transport_mode()?;
 
@@ -84,6 +167,9 @@ impl<'cfg> NoiseStream {
             stream: stream.to_owned(),
             noise,
             bytes_sent: 0,
+            previous_key,
+            suite: Suite::default(),
+            post_quantum: false,
         })
     }
 
@@ -91,6 +177,26 @@ impl<'cfg> NoiseStream {
         &self.stream
     }
 
+    /// The cipher suite of the session
+    pub fn suite(&self) -> Suite {
+        self.suite
+    }
+
+    /// The static key the peer authenticated with
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.noise.get_remote_static()
+    }
+
+    /// Whether an ML-KEM secret was mixed into the handshake
+    pub fn uses_post_quantum(&self) -> bool {
+        self.post_quantum
+    }
+
+    /// Whether the client authenticated the server with the key replaced by the last rotation
+    pub fn uses_previous_key(&self) -> bool {
+        self.previous_key
+    }
+
     pub async fn send(&mut self, msg: &Message) -> Result<(), HermodError> {
         let mut packet = [0u8; PACKET_MAXLENGTH];
         let msg_type = msg.get_type();
@@ -142,44 +248,310 @@ impl<'cfg> NoiseStream {
     }
 }
 
+/// The responder side of the identity hiding handshake once the first message is read.
+/// The client is looked up by its static key before the handshake is completed.
+pub struct IdentityHandshake {
+    noise: HandshakeState,
+    previous_key: bool,
+    payload: Vec<u8>,
+    suite: Suite,
+    psk: bool,
+    // The ML-KEM encapsulation key of a client using the hybrid handshake
+    kem_key: Option<Vec<u8>>,
+}
+
+impl<'cfg> IdentityHandshake {
+    /// Read the first handshake message, after its message type.
+    /// The client tells whether it uses a pre-shared key or the hybrid handshake, and offers its
+    /// cipher suites and uses the first one for the message. If the server
+    /// prefers another of the offered suites the client is asked to start over with it, once.
+    pub async fn accept<C: Config<'cfg>>(
+        config: &C,
+        stream: &mut TcpStream,
+    ) -> Result<Self, HermodError> {
+        let preferred = config.suites();
+        let mut retry: Option<Suite> = None;
+        loop {
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).await?;
+            let flags = header[0];
+            if flags & !(HANDSHAKE_PSK | HANDSHAKE_HYBRID) != 0 {
+                return Err(HermodError::new(HermodErrorKind::UnknownMessage));
+            }
+            let mut offered = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut offered).await?;
+            let kem_key = if flags & HANDSHAKE_HYBRID != 0 {
+                let mut key = vec![0u8; HYBRID_KEY_LEN];
+                stream.read_exact(&mut key).await?;
+                Some(key)
+            } else if config.post_quantum() {
+                return Err(post_quantum_required());
+            } else {
+                None
+            };
+            let prologue = prologue(flags, &offered, retry, kem_key.as_deref());
+            let mut len = [0u8; MSG_LENGTH_LEN];
+            stream.read_exact(&mut len).await?;
+            let len = u16::from_be_bytes(len) as usize;
+            if len > HERMOD_HS_IDENTITY_INIT_LEN + HERMOD_MAX_CERTIFICATE_LEN {
+                return Err(HermodError::new(HermodErrorKind::UnknownMessage));
+            }
+            let mut message = vec![0u8; len];
+            stream.read_exact(&mut message).await?;
+
+            // Suites unknown to this version are skipped, but the message is made with the first
+            let used = offered.first().and_then(|byte| Suite::from_byte(*byte));
+            let offered: Vec<Suite> = offered.into_iter().filter_map(Suite::from_byte).collect();
+            match suite::choose(&preferred, &offered) {
+                Some(suite) if Some(suite) == used => {
+                    let psk = flags & HANDSHAKE_PSK != 0;
+                    return IdentityHandshake::read(
+                        config, suite, psk, kem_key, &prologue, &message,
+                    );
+                }
+                Some(suite) if retry.is_none() => {
+                    stream
+                        .write_all(&[MessageType::RetrySuite as u8, suite.to_byte()])
+                        .await?;
+                    retry = Some(suite);
+                    let mut msg_type = [0u8];
+                    stream.read_exact(&mut msg_type).await?;
+                    if MessageType::from(msg_type[0]) != MessageType::IdentityInit {
+                        return Err(HermodError::new(HermodErrorKind::OutOfOrderMessage));
+                    }
+                }
+                _ => {
+                    stream.write_all(&[MessageType::Error as u8]).await?;
+                    return Err(suite_error("the client offered no accepted cipher suite"));
+                }
+            }
+        }
+    }
+
+    fn read<C: Config<'cfg>>(
+        config: &C,
+        suite: Suite,
+        psk: bool,
+        kem_key: Option<Vec<u8>>,
+        prologue: &[u8],
+        message: &[u8],
+    ) -> Result<Self, HermodError> {
+        // The key is only mixed in with the second message, once the client is known
+        let pattern = if psk || kem_key.is_some() {
+            IDENTITY_HIDING_PSK_PATTERN
+        } else {
+            IDENTITY_HIDING_PATTERN
+        };
+        let responder = |private_key: &[u8]| -> Result<HandshakeState, HermodError> {
+            Ok(builder(suite.params(pattern))
+                .local_private_key(private_key)
+                .prologue(prologue)
+                .build_responder()?)
+        };
+        let mut noise = responder(config.get_private_key())?;
+        let mut payload = vec![0u8; message.len()];
+
+        // Clients that have not learned about a rotated key yet still use the previous one
+        let mut previous_key = false;
+        let len = match noise.read_message(message, &mut payload) {
+            Ok(len) => len,
+            Err(e) => {
+                let private_key = config.get_previous_private_key().ok_or(e)?;
+                noise = responder(private_key)?;
+                previous_key = true;
+                noise.read_message(message, &mut payload)?
+            }
+        };
+        payload.truncate(len);
+
+        Ok(IdentityHandshake {
+            noise,
+            previous_key,
+            payload,
+            suite,
+            psk,
+            kem_key,
+        })
+    }
+
+    /// The static key of the client
+    pub fn remote_static(&self) -> &[u8] {
+        self.noise
+            .get_remote_static()
+            .expect("The first handshake message carries the static key of the client")
+    }
+
+    /// The certificate of the client, empty if it has none
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Answer the client, once it is known to be authorized. A client with a pre-shared key
+    /// must have used it, `psk` is the key of the client.
+    pub async fn complete(
+        mut self,
+        stream: &mut TcpStream,
+        psk: Option<&[u8]>,
+    ) -> Result<NoiseStream, HermodError> {
+        match (self.psk, psk) {
+            (true, Some(_)) | (false, None) => (),
+            (false, Some(_)) => return Err(psk_required()),
+            (true, None) => {
+                let msg = String::from("the client has no pre-shared key");
+                return Err(HermodError::new(HermodErrorKind::AccessDenied(msg)));
+            }
+        }
+        let ciphertext = match self.kem_key {
+            Some(ref key) => {
+                let (ciphertext, shared) = hybrid::encapsulate(key)?;
+                let psk = hybrid::handshake_psk(&shared, psk);
+                self.noise.set_psk(2, psk.as_bytes())?;
+                Some(ciphertext)
+            }
+            None => {
+                if let Some(psk) = psk {
+                    self.noise.set_psk(2, psk)?;
+                }
+                None
+            }
+        };
+        server_handshake(
+            stream,
+            &mut self.noise,
+            self.previous_key,
+            ciphertext.as_deref(),
+        )
+        .await?;
+
+        Ok(NoiseStream {
+            stream: stream.to_owned(),
+            noise: self.noise.into_transport_mode()?,
+            bytes_sent: 0,
+            previous_key: self.previous_key,
+            suite: self.suite,
+            post_quantum: self.kem_key.is_some(),
+        })
+    }
+}
+
+fn builder<'builder>(params: NoiseParams) -> Builder<'builder> {
+    Builder::with_resolver(params, suite::resolver())
+}
+
+fn psk_required() -> HermodError {
+    let msg = String::from("the client must use its pre-shared key");
+    HermodError::new(HermodErrorKind::AccessDenied(msg))
+}
+
+fn post_quantum_required() -> HermodError {
+    let msg = String::from("the server requires the post-quantum handshake");
+    HermodError::new(HermodErrorKind::AccessDenied(msg))
+}
+
+fn suite_error(msg: &str) -> HermodError {
+    HermodError::new(HermodErrorKind::CipherSuite(msg.to_owned()))
+}
+
+/// The negotiation sent in the clear ahead of the first handshake message: the flags, the offered
+/// suites, the suite the server asked to start over with and the encapsulation key. Both sides
+/// mix it into the handshake, so the handshake fails if any of it was changed on the way.
+fn prologue(flags: u8, offered: &[u8], retry: Option<Suite>, kem_key: Option<&[u8]>) -> Vec<u8> {
+    let mut prologue = vec![flags, offered.len() as u8];
+    prologue.extend(offered);
+    prologue.extend(retry.map(|suite| suite.to_byte()));
+    prologue.extend(kem_key.unwrap_or_default());
+    prologue
+}
+
+/// Sends the `flags` and the `offered` suites with the message made with the first. When the
+/// handshake completes the server follows it with the announcement of its new key if it used its
+/// previous key.
 async fn client_handshake(
     stream: &mut TcpStream,
     hs: &mut HandshakeState,
-    token: &[u8],
-) -> Result<(), snow::error::Error> {
-    let mut packet = [0u8; PACKET_MAXLENGTH];
-
-    packet[0] = MessageType::Init as u8;
-
-    let mut i = 1;
-    token.iter().for_each(|byte| {
-        packet[i] = *byte;
-        i += 1;
-    });
-
-    let _len = hs.write_message(&[], &mut packet[13..])?;
-    stream
-        .write_all(&packet[..HERMOD_HS_INIT_LEN])
-        .await
-        .unwrap();
-
-    let mut read_buffer = vec![0u8; HERMOD_HS_RESP_LEN + MSG_TYPE_LEN];
-    let mut resp_buffer = vec![0u8; HERMOD_HS_RESP_LEN];
-    stream.read_exact(&mut read_buffer).await.unwrap();
-    hs.read_message(&read_buffer[MSG_TYPE_LEN..], &mut resp_buffer)?;
-    Ok(())
+    flags: u8,
+    offered: &[u8],
+    peer: &Peer,
+    kem: Option<&KemKeyPair>,
+) -> Result<Answer, HermodError> {
+    // A certificate is sent as the payload, encrypted like the static key
+    let payload = peer
+        .get_certificate()
+        .map(|certificate| bincode::serialize(certificate).unwrap())
+        .unwrap_or_default();
+    let mut message = vec![0u8; HERMOD_HS_IDENTITY_INIT_LEN + payload.len()];
+    let len = hs.write_message(&payload, &mut message)?;
+
+    let mut packet = vec![MessageType::IdentityInit as u8, flags, offered.len() as u8];
+    packet.extend(offered);
+    if let Some(kem) = kem {
+        packet.extend(kem.encapsulation_key());
+    }
+    packet.extend(&(len as u16).to_be_bytes());
+    packet.extend(&message[..len]);
+    stream.write_all(&packet).await?;
+
+    let mut msg_type = [0u8];
+    stream.read_exact(&mut msg_type).await?;
+    match MessageType::from(msg_type[0]) {
+        MessageType::Response | MessageType::KeyRotation => {
+            // The server encapsulates a shared secret before its handshake message
+            let psk = match kem {
+                Some(kem) => {
+                    let mut ciphertext = vec![0u8; HYBRID_CIPHERTEXT_LEN];
+                    stream.read_exact(&mut ciphertext).await?;
+                    let shared = kem.decapsulate(&ciphertext)?;
+                    Some(hybrid::handshake_psk(&shared, peer.get_psk()))
+                }
+                None => peer.get_psk().map(SecretKey::new),
+            };
+            if let Some(psk) = psk {
+                hs.set_psk(2, psk.as_bytes())?;
+            }
+            let mut read_buffer = vec![0u8; HERMOD_HS_RESP_LEN];
+            let mut resp_buffer = vec![0u8; HERMOD_HS_RESP_LEN];
+            stream.read_exact(&mut read_buffer).await?;
+            hs.read_message(&read_buffer, &mut resp_buffer)?;
+            Ok(Answer::Complete(
+                MessageType::from(msg_type[0]) == MessageType::KeyRotation,
+            ))
+        }
+        MessageType::RetrySuite => {
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).await?;
+            Suite::from_byte(byte[0])
+                .map(Answer::Retry)
+                .ok_or_else(|| suite_error("the server asked for an unknown suite"))
+        }
+        MessageType::CookieReply => {
+            let mut cookie = vec![0u8; COOKIE_LEN];
+            stream.read_exact(&mut cookie).await?;
+            Ok(Answer::Cookie(cookie))
+        }
+        MessageType::Error => Err(suite_error(
+            "the server accepts none of the configured cipher suites",
+        )),
+        _ => Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+    }
 }
 
 async fn server_handshake(
     stream: &mut TcpStream,
     hs: &mut HandshakeState,
-    msg: &Message,
+    previous_key: bool,
+    ciphertext: Option<&[u8]>,
 ) -> Result<(), HermodError> {
-    let mut resp_buffer = vec![0u8; 48 + MSG_TYPE_LEN];
+    let mut resp_buffer = vec![0u8; 48];
 
-    hs.read_message(&msg.get_payload()[12..], &mut [])?;
-    let _len = hs.write_message(&[], &mut resp_buffer[MSG_TYPE_LEN..])?;
-    resp_buffer[0] = MessageType::Response as u8;
-    stream.write_all(&resp_buffer[..]).await?;
+    let _len = hs.write_message(&[], &mut resp_buffer)?;
+    // Older clients ignore the type of the response, newer ones wait for the announcement
+    let mut packet = vec![if previous_key {
+        MessageType::KeyRotation as u8
+    } else {
+        MessageType::Response as u8
+    }];
+    packet.extend(ciphertext.unwrap_or_default());
+    packet.extend(&resp_buffer);
+    stream.write_all(&packet).await?;
     Ok(())
 }
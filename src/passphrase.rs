@@ -0,0 +1,172 @@
+use crate::consts::*;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::secret::SecretKey;
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use rand::prelude::*;
+use skymd::chacha20poly1305::{self, KEY_LEN, NONCE_LEN, TAG_LEN};
+use zeroize::Zeroizing;
+
+const SALT_LEN: usize = 16;
+
+/// The parts of an encrypted key
+struct Sealed {
+    salt: [u8; SALT_LEN],
+    ciphertext: Vec<u8>,
+    tag: [u8; TAG_LEN],
+}
+
+/// Whether a stored private key is protected by a passphrase
+pub fn is_encrypted(value: &str) -> bool {
+    value.trim().starts_with(ENCRYPTED_KEY_PREFIX)
+}
+
+/// Encrypt a private key with a key derived from `passphrase`.
+/// The result is stored in place of the base64 encoded key:
+/// `hermod-enc-v1:` followed by base64(salt || ciphertext || tag).
+pub fn encrypt(key: &[u8], passphrase: &str) -> Result<String, HermodError> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let secret = derive_key(passphrase, &salt)?;
+
+    // Every encryption uses a fresh salt, and thereby a fresh key, so a fixed nonce is safe
+    let nonce = [0u8; NONCE_LEN];
+    let mut ciphertext = vec![0u8; key.len()];
+    let mut tag = [0u8; TAG_LEN];
+    skymd::skymd_init();
+    chacha20poly1305::encrypt(
+        &secret,
+        &nonce,
+        ENCRYPTED_KEY_PREFIX.as_bytes(),
+        key,
+        &mut ciphertext,
+        &mut tag,
+    );
+
+    let mut blob = salt.to_vec();
+    blob.extend(&ciphertext);
+    blob.extend(&tag);
+    Ok(format!("{}{}", ENCRYPTED_KEY_PREFIX, base64::encode(&blob)))
+}
+
+/// Decrypt a private key previously encrypted with `encrypt`
+pub fn decrypt(value: &str, passphrase: &str) -> Result<SecretKey, HermodError> {
+    let sealed = split(value)?;
+    let secret = derive_key(passphrase, &sealed.salt)?;
+
+    let nonce = [0u8; NONCE_LEN];
+    let mut key = Zeroizing::new(vec![0u8; sealed.ciphertext.len()]);
+    skymd::skymd_init();
+    chacha20poly1305::decrypt(
+        &secret,
+        &nonce,
+        ENCRYPTED_KEY_PREFIX.as_bytes(),
+        &mut key,
+        &sealed.ciphertext,
+        &sealed.tag,
+    )
+    .map_err(|_| error("wrong passphrase"))?;
+    Ok(SecretKey::new(&key))
+}
+
+/// Check that an encrypted key is well formed, without decrypting it
+pub fn check(value: &str) -> Result<(), HermodError> {
+    split(value).map(|_| ())
+}
+
+/// Read the passphrase for unlocking a private key.
+/// The passphrase is taken from the HERMOD_PASSPHRASE environment variable, the passphrase
+/// file if given, the file named by HERMOD_PASSPHRASE_FILE or, as a last resort, asked for
+/// on the terminal.
+pub fn read(prompt: &str, file: Option<&Path>) -> Result<Zeroizing<String>, HermodError> {
+    if let Ok(passphrase) = std::env::var(HERMOD_PASSPHRASE_VAR) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+
+    let file = file
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(HERMOD_PASSPHRASE_FILE_VAR).map(PathBuf::from));
+    if let Some(file) = file {
+        let content = std::fs::read_to_string(&file)
+            .map(Zeroizing::new)
+            .map_err(|e| error(&format!("failed to read {}: {}", file.display(), e)))?;
+        // Only the first line is used, so that files ending with a newline work as expected
+        let passphrase = content.lines().next().unwrap_or_default().to_owned();
+        return Ok(Zeroizing::new(passphrase));
+    }
+
+    Ok(Zeroizing::new(rpassword::read_password_from_tty(Some(
+        prompt,
+    ))?))
+}
+
+/// Ask for a new passphrase on the terminal, an empty passphrase means no passphrase
+pub fn read_new() -> Result<Option<Zeroizing<String>>, HermodError> {
+    let passphrase = Zeroizing::new(rpassword::read_password_from_tty(Some(
+        "Enter new passphrase (empty for no passphrase): ",
+    ))?);
+    let repeated = Zeroizing::new(rpassword::read_password_from_tty(Some(
+        "Enter the same passphrase again: ",
+    ))?);
+    if passphrase != repeated {
+        return Err(error("the passphrases do not match"));
+    }
+    if passphrase.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(passphrase))
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>, HermodError> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut *key)
+        .map_err(|e| error(&e.to_string()))?;
+    Ok(key)
+}
+
+/// Split an encrypted key into its salt, ciphertext and tag
+fn split(value: &str) -> Result<Sealed, HermodError> {
+    let blob = value
+        .trim()
+        .strip_prefix(ENCRYPTED_KEY_PREFIX)
+        .ok_or_else(|| error("not an encrypted key"))?;
+    let blob = base64::decode(blob)?;
+    if blob.len() != SALT_LEN + KEY_LEN + TAG_LEN {
+        return Err(error("invalid length of the encrypted key"));
+    }
+
+    let (salt, rest) = blob.split_at(SALT_LEN);
+    let (ciphertext, tag) = rest.split_at(KEY_LEN);
+    Ok(Sealed {
+        salt: salt.try_into().unwrap(),
+        ciphertext: ciphertext.to_vec(),
+        tag: tag.try_into().unwrap(),
+    })
+}
+
+fn error(msg: &str) -> HermodError {
+    HermodError::new(HermodErrorKind::Passphrase(msg.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_and_decrypt_key() {
+        let key = [7u8; 32];
+        let encrypted = encrypt(&key, "correct horse").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(check(&encrypted).is_ok());
+        assert_eq!(
+            decrypt(&encrypted, "correct horse").unwrap().as_bytes(),
+            key
+        );
+        assert!(decrypt(&encrypted, "wrong horse").is_err());
+    }
+}
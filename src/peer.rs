@@ -1,13 +1,22 @@
-use crate::config::{ClientConfig, SERVER_CONFIG};
+use crate::certificate::Certificate;
+use crate::config::{ClientConfig, SERVER_CONFIG, SERVER_SETTINGS};
 use crate::error::{HermodError, HermodErrorKind};
-use crate::host::{self, Host};
+use crate::genkey;
+use crate::host::Host;
 use crate::identity::{Identity, KNOWN_CLIENTS};
 use crate::message::{Message, MessageType};
-use crate::noise::NoiseStream;
+use crate::noise::{IdentityHandshake, NoiseStream};
+use crate::rotation::KeyRotation;
+use crate::secret::SecretKey;
+use crate::suite::Suite;
 
 use std::fmt;
+use std::time::{Duration, Instant};
 
 use async_std::net::TcpStream;
+use async_std::task;
+
+use chrono::Utc;
 
 pub enum Peer {
     Identity(Identity),
@@ -21,9 +30,8 @@ impl fmt::Display for Peer {
 }
 
 impl Peer {
-    pub async fn new_server_peer(alias: &str) -> Result<Self, HermodError> {
-        let host = host::load_host_async(alias).await?;
-        Ok(Peer::Host(host))
+    pub fn new_server_peer(host: &Host) -> Self {
+        Peer::Host(host.clone())
     }
 
     pub async fn new_client_peer(id: &str) -> Result<Self, HermodError> {
@@ -31,13 +39,26 @@ impl Peer {
         let client = clients
             .get(id)
             .ok_or_else(|| HermodError::new(HermodErrorKind::UnknownIdentity))?;
-        let id_token = client.get_id().to_owned();
-        let client_key = client.get_public_key().to_vec();
+        client.check_access(Utc::now())?;
+        Ok(Peer::Identity(client.clone()))
+    }
 
-        Ok(Peer::Identity(Identity {
-            id_token,
-            client_key,
-        }))
+    /// A client admitted by a certificate signed by the certificate authority of the server.
+    /// The id token must not be one of a known client, whose sessions it would share.
+    pub async fn new_certified_peer(certificate: &Certificate) -> Result<Self, HermodError> {
+        let ca_key = SERVER_CONFIG.ca_key().ok_or_else(|| {
+            HermodError::new(HermodErrorKind::Certificate(String::from(
+                "the server does not trust a certificate authority",
+            )))
+        })?;
+        let principals = SERVER_SETTINGS.read().unwrap().ca_principals.clone();
+        let id = certificate.verify(ca_key, &principals)?;
+        if KNOWN_CLIENTS.lock().await.contains_key(&id.id_token) {
+            return Err(HermodError::new(HermodErrorKind::Certificate(
+                String::from("issued for the id token of an authorized client"),
+            )));
+        }
+        Ok(Peer::Identity(id))
     }
 
     pub fn get_id(&self) -> &str {
@@ -53,11 +74,54 @@ impl Peer {
             Peer::Host(host) => &host.server_key,
         }
     }
+
+    /// The key mixed into the handshake, if the client has one
+    pub fn get_psk(&self) -> Option<&[u8]> {
+        match self {
+            Peer::Identity(id) => id.psk.as_ref().map(SecretKey::as_bytes),
+            Peer::Host(host) => host.psk(),
+        }
+    }
+
+    /// The certificate a client presents instead of being known to the server
+    pub fn get_certificate(&self) -> Option<&Certificate> {
+        match self {
+            Peer::Identity(_) => None,
+            Peer::Host(host) => host.certificate(),
+        }
+    }
 }
 
 pub struct Endpoint {
     peer: Peer,
     stream: NoiseStream,
+    limiter: Option<RateLimiter>,
+}
+
+/// Keeps the average throughput of a connection below a number of bytes per second
+struct RateLimiter {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    async fn consume(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        let expected = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            task::sleep(expected - elapsed).await;
+        }
+    }
 }
 
 impl<'e> Endpoint {
@@ -68,7 +132,20 @@ impl<'e> Endpoint {
     ) -> Result<Self, HermodError> {
         let stream = NoiseStream::new_initiator(&peer, cfg, stream).await?;
 
-        Ok(Endpoint { peer, stream })
+        let mut endpoint = Endpoint {
+            peer,
+            stream,
+            limiter: None,
+        };
+        // A server that rotated its key announces the new key right after the handshake
+        if endpoint.uses_previous_key() {
+            let msg = endpoint.stream.recv().await?;
+            if msg.get_type() != MessageType::KeyRotation {
+                return Err(HermodError::new(HermodErrorKind::OutOfOrderMessage));
+            }
+            endpoint.update_server_key(&msg)?;
+        }
+        Ok(endpoint)
     }
 
     pub async fn server(
@@ -78,7 +155,26 @@ impl<'e> Endpoint {
     ) -> Result<Self, HermodError> {
         let stream = NoiseStream::new_responder(&peer, &*SERVER_CONFIG, stream, msg).await?;
 
-        Ok(Endpoint { peer, stream })
+        Ok(Endpoint {
+            peer,
+            stream,
+            limiter: None,
+        })
+    }
+
+    /// Complete a handshake in which the client was identified by its static key
+    pub async fn server_identified(
+        stream: &mut TcpStream,
+        peer: Peer,
+        handshake: IdentityHandshake,
+    ) -> Result<Self, HermodError> {
+        let stream = handshake.complete(stream, peer.get_psk()).await?;
+
+        Ok(Endpoint {
+            peer,
+            stream,
+            limiter: None,
+        })
     }
 
     pub fn get_stream(&self) -> &TcpStream {
@@ -89,16 +185,69 @@ impl<'e> Endpoint {
         &self.peer
     }
 
+    /// The cipher suite negotiated for the session
+    pub fn suite(&self) -> Suite {
+        self.stream.suite()
+    }
+
+    /// The static key the peer authenticated the session with
+    pub fn remote_static(&self) -> Option<&[u8]> {
+        self.stream.remote_static()
+    }
+
+    /// Whether the handshake of the session was the hybrid post-quantum one
+    pub fn uses_post_quantum(&self) -> bool {
+        self.stream.uses_post_quantum()
+    }
+
+    /// Whether the client authenticated the server with the key replaced by the last rotation
+    pub fn uses_previous_key(&self) -> bool {
+        self.stream.uses_previous_key()
+    }
+
+    /// Limit the throughput of the connection to `rate` bytes per second, in both directions
+    pub fn set_rate_limit(&mut self, rate: Option<u64>) {
+        self.limiter = rate.filter(|rate| *rate > 0).map(RateLimiter::new);
+    }
+
     pub async fn close(&mut self) -> Result<(), HermodError> {
         let msg = Message::new(MessageType::Close, &[]);
         self.stream.send(&msg).await
     }
 
     pub async fn send(&mut self, msg: &Message) -> Result<(), HermodError> {
-        self.stream.send(msg).await
+        self.stream.send(msg).await?;
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.consume(msg.len()).await;
+        }
+        Ok(())
     }
 
     pub async fn recv(&mut self) -> Result<Message, HermodError> {
-        self.stream.recv().await
+        let msg = self.stream.recv().await?;
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.consume(msg.len()).await;
+        }
+        Ok(msg)
+    }
+
+    /// Verify a key rotation announced by the server and store the new key in the host file
+    fn update_server_key(&mut self, msg: &Message) -> Result<(), HermodError> {
+        let host = match self.peer {
+            Peer::Host(ref mut host) => host,
+            Peer::Identity(_) => return Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+        };
+        let rotation: KeyRotation = bincode::deserialize(msg.get_payload())
+            .map_err(|e| HermodError::new(HermodErrorKind::KeyRotation(e.to_string())))?;
+        rotation.verify(host.server_key())?;
+
+        *host = host.clone().set_server_key(&rotation.public_key()?);
+        host.write_to_file()?;
+        println!(
+            "The server rotated its key, the new key {} is stored for {}",
+            genkey::fingerprint(host.server_key()),
+            host.alias()
+        );
+        Ok(())
     }
 }
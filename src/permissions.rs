@@ -0,0 +1,155 @@
+use crate::consts::HERMOD_BASE_DIR;
+use crate::error::{HermodError, HermodErrorKind};
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+
+const PRIVATE_FILE_MODE: u32 = 0o600;
+const PRIVATE_DIR_MODE: u32 = 0o700;
+const PUBLIC_FILE_MODE: u32 = 0o644;
+
+static NEXT_TEMPORARY: AtomicU64 = AtomicU64::new(0);
+
+/// Create a directory, and any missing parents, only accessible by the owner.
+/// An existing directory under ~/.hermod is made private too, others are only warned about,
+/// they may be shared on purpose.
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(PRIVATE_DIR_MODE)
+        .create(path)?;
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+    let base_dir = dirs::home_dir().map(|home| home.join(HERMOD_BASE_DIR));
+    if base_dir.is_some_and(|base_dir| path.starts_with(base_dir)) {
+        fs::set_permissions(path, fs::Permissions::from_mode(PRIVATE_DIR_MODE))
+    } else {
+        warn!(
+            "{} is accessible by others (mode {:04o}), run 'chmod 700 {}'",
+            path.display(),
+            mode & 0o7777,
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Create or truncate a file only accessible by the owner
+pub fn create_private_file(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(PRIVATE_FILE_MODE)
+        .open(path)?;
+    // The mode is only applied to new files, tighten the permissions of existing ones
+    file.set_permissions(fs::Permissions::from_mode(PRIVATE_FILE_MODE))?;
+    Ok(file)
+}
+
+/// Write `content` to a file only accessible by the owner. The content is written to a
+/// temporary file first and moved in place, so the file is never left partially written.
+pub fn write_private_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    write_atomically(path, content, PRIVATE_FILE_MODE)
+}
+
+/// Write `content` to a file anyone may read, replaced at once like by `write_private_file`
+pub fn write_public_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    write_atomically(path, content, PUBLIC_FILE_MODE)
+}
+
+fn write_atomically(path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+    let tmp_path = temporary_path(path);
+    let res = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, path)
+        });
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/// A path next to `path` to stage a new version of it, unique to this process and call
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        NEXT_TEMPORARY.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// Wait for an exclusive advisory lock on the file `path`, created if missing. The lock is
+/// held until the returned file is closed.
+pub fn lock_file(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .mode(PRIVATE_FILE_MODE)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Refuse to use a file holding a private key if anyone but the owner can access it
+pub fn check_private_file(path: &Path) -> Result<(), HermodError> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(HermodError::new(HermodErrorKind::InsecurePermissions(
+            format!("{} (mode {:04o})", path.display(), mode & 0o7777),
+        )));
+    }
+    Ok(())
+}
+
+/// Warn if a file that decides who may connect can be modified by anyone but the owner
+pub fn warn_if_writable(path: &Path) {
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o022 != 0 {
+            warn!(
+                "{} is writable by others (mode {:04o}), run 'chmod go-w {}'",
+                path.display(),
+                mode & 0o7777,
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_files_are_only_accessible_by_the_owner() {
+        let dir = std::env::temp_dir().join(format!("hermod-permissions-{}", std::process::id()));
+        let path = dir.join("keys").join("key");
+        create_private_dir(path.parent().unwrap()).unwrap();
+        write_private_file(&path, b"secret").unwrap();
+        assert!(check_private_file(&path).is_ok());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(check_private_file(&path).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 use crate::config::ClientConfig;
 use crate::consts::*;
-use crate::error::{HermodError, HermodErrorKind};
+use crate::error::{HermodError, HermodErrorKind, RemoteError, RemoteErrorKind};
+use crate::filter::Filter;
 use crate::message::{Message, MessageType};
 use crate::peer::Endpoint;
 
@@ -13,7 +14,9 @@ use async_std::path::Path;
 use async_std::prelude::*;
 use async_std::sync::{Receiver, Sender};
 
-use futures::{stream, Stream, StreamExt};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 
 use indicatif::{ProgressBar, ProgressStyle};
 
@@ -21,8 +24,6 @@ use log::{error, info};
 
 use serde::{Deserialize, Serialize};
 
-use walkdir::WalkDir;
-
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct PathList {
     paths: Vec<String>,
@@ -90,10 +91,87 @@ impl Metadata {
     }
 }
 
+/// Information about a remote path, sent in response to a `Stat` request
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileStat {
+    pub path: String,
+    pub len: u64,
+    pub dir: bool,
+    pub symlink: bool,
+    pub mode: u32,
+    pub modified: Option<u64>,
+}
+
+impl FileStat {
+    pub async fn from_path(path: &PathBuf) -> Result<Self, std::io::Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let metadata = async_std::fs::symlink_metadata(&path).await?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
+
+        Ok(FileStat {
+            path: path.to_string_lossy().into_owned(),
+            len: metadata.len(),
+            dir: metadata.is_dir(),
+            symlink: metadata.file_type().is_symlink(),
+            mode: metadata.permissions().mode() & 0o7777,
+            modified,
+        })
+    }
+
+    /// The last component of the path, with a trailing slash for directories
+    pub fn name(&self) -> String {
+        let name = std::path::Path::new(&self.path).file_name().map_or_else(
+            || self.path.clone(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        if self.dir {
+            format!("{}/", name)
+        } else {
+            name
+        }
+    }
+}
+
+impl fmt::Display for FileStat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file_type = if self.symlink {
+            "symbolic link"
+        } else if self.dir {
+            "directory"
+        } else {
+            "regular file"
+        };
+        writeln!(f, "  File: {}", self.path)?;
+        writeln!(f, "  Type: {}", file_type)?;
+        writeln!(f, "  Size: {}", self.len)?;
+        writeln!(f, "  Mode: {:04o}", self.mode)?;
+        match self.modified {
+            Some(secs) => {
+                let time: chrono::DateTime<chrono::Local> =
+                    (std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)).into();
+                write!(f, "Modify: {}", time.format("%Y-%m-%d %H:%M:%S %z"))
+            }
+            None => write!(f, "Modify: -"),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum RequestMethod {
     Upload = 1,
     Download,
+    Mkdir,
+    Remove,
+    RemoveAll,
+    Rename,
+    Stat,
+    List,
+    Glob,
 }
 
 impl fmt::Display for RequestMethod {
@@ -101,15 +179,24 @@ impl fmt::Display for RequestMethod {
         match self {
             RequestMethod::Upload => write!(f, "Upload"),
             RequestMethod::Download => write!(f, "Download"),
+            RequestMethod::Mkdir => write!(f, "Mkdir"),
+            RequestMethod::Remove => write!(f, "Remove"),
+            RequestMethod::RemoveAll => write!(f, "RemoveAll"),
+            RequestMethod::Rename => write!(f, "Rename"),
+            RequestMethod::Stat => write!(f, "Stat"),
+            RequestMethod::List => write!(f, "List"),
+            RequestMethod::Glob => write!(f, "Glob"),
         }
     }
 }
 
-#[derive(Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Request {
     source: PathBuf,
     destination: PathBuf,
     method: RequestMethod,
+    filter: Filter,
+    compress: bool,
 }
 
 impl fmt::Debug for Request {
@@ -129,6 +216,14 @@ impl fmt::Debug for Request {
                 self.source.as_path().display(),
                 self.destination.as_path().display(),
             ),
+            RequestMethod::Rename => write!(
+                f,
+                "{} {} to {}",
+                self.method,
+                self.source.as_path().display(),
+                self.destination.as_path().display(),
+            ),
+            _ => write!(f, "{} {}", self.method, self.source.as_path().display()),
         }
     }
 }
@@ -143,9 +238,14 @@ impl fmt::Display for Request {
                 self.source.file_name().unwrap(),
                 self.destination.as_path().display(),
             ),
-            RequestMethod::Download => {
-                write!(f, "{} {}", self.method, self.source.as_path().display(),)
-            }
+            RequestMethod::Rename => write!(
+                f,
+                "{} {} to {}",
+                self.method,
+                self.source.as_path().display(),
+                self.destination.as_path().display(),
+            ),
+            _ => write!(f, "{} {}", self.method, self.source.as_path().display()),
         }
     }
 }
@@ -169,36 +269,50 @@ impl Request {
             source,
             destination,
             method,
+            filter: Filter::default(),
+            compress: false,
         })
     }
 
+    /// Restrict which files are transferred when the source is a directory
+    pub fn with_filter(mut self, filter: &Filter) -> Self {
+        self.filter = filter.clone();
+        self
+    }
+
+    /// Compress the file content while it is transferred
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
     // FIXME Find a better way to handle directories
     pub fn dir(
         source: &str,
         destination: &str,
         method: RequestMethod,
+        filter: &Filter,
     ) -> Result<Vec<Request>, HermodError> {
         let mut requests = Vec::new();
 
         let source = PathBuf::from(source);
         let file_name = source.file_name();
 
-        for entry in WalkDir::new(&source).into_iter().filter_map(|e| e.ok()) {
-            let path = entry.path();
-            if !path.is_dir() {
-                let mut destination = PathBuf::from(destination);
-                if let Some(dir) = file_name {
-                    destination.push(dir);
-                }
-                let mut dest = path.strip_prefix(&source).unwrap().to_path_buf();
-                dest.pop(); // Pop filename
-                destination.push(dest);
-                requests.push(Request {
-                    source: path.to_path_buf(),
-                    destination,
-                    method,
-                });
+        for path in filter.walk(&source)? {
+            let mut destination = PathBuf::from(destination);
+            if let Some(dir) = file_name {
+                destination.push(dir);
             }
+            let mut dest = path.strip_prefix(&source).unwrap().to_path_buf();
+            dest.pop(); // Pop filename
+            destination.push(dest);
+            requests.push(Request {
+                source: path,
+                destination,
+                method,
+                filter: Filter::default(),
+                compress: false,
+            });
         }
         Ok(requests)
     }
@@ -206,17 +320,41 @@ impl Request {
     // FIXME Ugly hack for creating multiple requests from one config
     // Should probably be one constructor for upload and one for download
     pub fn from(config: &ClientConfig<'_>) -> Result<Vec<Request>, HermodError> {
-        let destination = config.destination;
         let method = config.request;
+        // Remote paths are relative to the configured remote directory
+        let destination = match method {
+            RequestMethod::Upload | RequestMethod::Rename => config.remote_path(config.destination),
+            _ if config.destination.is_empty() => String::from("."),
+            _ => config.destination.to_owned(),
+        };
+
         let mut requests = Vec::new();
         for path in &config.source {
-            let source = PathBuf::from(path);
-            if method == RequestMethod::Upload && source.is_dir() {
-                requests.append(&mut Request::dir(path, destination, method)?);
+            if method == RequestMethod::Upload {
+                if PathBuf::from(path).is_dir() {
+                    requests.append(&mut Request::dir(
+                        path,
+                        &destination,
+                        method,
+                        &config.filter,
+                    )?);
+                } else {
+                    requests.push(Request::file(path, &destination, method)?);
+                }
             } else {
-                requests.push(Request::file(path, destination, method)?);
+                let source = config.remote_path(path);
+                requests.push(Request::file(&source, &destination, method)?);
             }
         }
+
+        let requests = requests
+            .into_iter()
+            .map(|request| {
+                request
+                    .with_filter(&config.filter)
+                    .with_compression(config.compression)
+            })
+            .collect();
         Ok(requests)
     }
 
@@ -229,6 +367,13 @@ impl Request {
         let res = match self.method {
             RequestMethod::Upload => self.download_server(endpoint).await,
             RequestMethod::Download => self.upload_server(endpoint).await,
+            RequestMethod::Stat => self.stat_server(endpoint).await,
+            RequestMethod::List => self.list_server(endpoint).await,
+            RequestMethod::Glob => self.glob_server(endpoint).await,
+            RequestMethod::Mkdir
+            | RequestMethod::Remove
+            | RequestMethod::RemoveAll
+            | RequestMethod::Rename => self.manage_server(endpoint).await,
         };
         info!("Responded to request from {}", endpoint.get_peer(),);
         res
@@ -252,21 +397,259 @@ impl Request {
     }
 
     pub async fn exec(&self, endpoint: &mut Endpoint) -> Result<(), HermodError> {
+        // Patterns are expanded by the server before any file is transferred
+        for request in self.expand(endpoint).await? {
+            request.exec_literal(endpoint).await?;
+        }
+        Ok(())
+    }
+
+    /// Execute the request with its source taken as a path, even if it looks like a pattern
+    pub async fn exec_literal(&self, endpoint: &mut Endpoint) -> Result<(), HermodError> {
         self.send_request(endpoint).await?;
         match self.method {
             RequestMethod::Upload => self.upload_client(endpoint).await,
             RequestMethod::Download => self.download_client(endpoint).await,
+            RequestMethod::Glob => {
+                for path in recv_paths(endpoint).await? {
+                    println!("{}", path);
+                }
+                Ok(())
+            }
+            RequestMethod::Stat => {
+                println!("{}", self.recv_stat(endpoint).await?);
+                Ok(())
+            }
+            RequestMethod::List => {
+                for entry in self.recv_list(endpoint).await? {
+                    println!("{}", entry.name());
+                }
+                Ok(())
+            }
+            RequestMethod::Mkdir
+            | RequestMethod::Remove
+            | RequestMethod::RemoveAll
+            | RequestMethod::Rename => recv_status(endpoint).await,
+        }
+    }
+
+    async fn manage_server(&self, endpoint: &mut Endpoint) -> Result<(), HermodError> {
+        let res = match self.method {
+            RequestMethod::Mkdir => fs::create_dir_all(&self.source).await,
+            RequestMethod::Remove => remove_path(&self.source, false).await,
+            RequestMethod::RemoveAll => remove_path(&self.source, true).await,
+            RequestMethod::Rename => {
+                // Move the source into the destination if it is an existing directory, like mv
+                let mut destination = self.destination.clone();
+                if destination.is_dir() {
+                    if let Some(file_name) = self.source.file_name() {
+                        destination.push(file_name);
+                    }
+                }
+                fs::rename(&self.source, &destination).await
+            }
+            _ => unreachable!("{} is not a file management request", self.method),
+        };
+
+        match res {
+            Ok(()) => endpoint.send(&Message::new(MessageType::Okay, &[])).await,
+            Err(e) => {
+                error!("Failed to {}: {}", self, e);
+                send_error(endpoint, &RemoteError::from_io(&e, &self.source)).await
+            }
+        }
+    }
+
+    async fn stat_server(&self, endpoint: &mut Endpoint) -> Result<(), HermodError> {
+        match FileStat::from_path(&self.source).await {
+            Ok(stat) => {
+                let enc_stat = bincode::serialize(&stat).unwrap();
+                endpoint
+                    .send(&Message::new(MessageType::Metadata, &enc_stat))
+                    .await
+            }
+            Err(e) => {
+                error!("Failed to {}: {}", self, e);
+                send_error(endpoint, &RemoteError::from_io(&e, &self.source)).await
+            }
+        }
+    }
+
+    async fn list_server(&self, endpoint: &mut Endpoint) -> Result<(), HermodError> {
+        let entries = match list_path(&self.source).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to {}: {}", self, e);
+                return send_error(endpoint, &RemoteError::from_io(&e, &self.source)).await;
+            }
+        };
+
+        // Split the listing over several messages if it does not fit in one
+        let mut chunk: Vec<FileStat> = Vec::new();
+        let mut len = 0;
+        for entry in entries {
+            let entry_len = bincode::serialized_size(&entry).unwrap() as usize;
+            if len + entry_len >= MSG_PAYLOAD_LEN {
+                let payload = bincode::serialize(&chunk).unwrap();
+                endpoint
+                    .send(&Message::new(MessageType::Payload, &payload))
+                    .await?;
+                chunk.clear();
+                len = 0;
+            }
+            len += entry_len;
+            chunk.push(entry);
+        }
+        let payload = bincode::serialize(&chunk).unwrap();
+        endpoint
+            .send(&Message::new(MessageType::Payload, &payload))
+            .await?;
+        endpoint.send(&Message::new(MessageType::EOF, &[])).await
+    }
+
+    async fn glob_server(&self, endpoint: &mut Endpoint) -> Result<(), HermodError> {
+        let pattern = self.source.to_string_lossy();
+        let options = glob::MatchOptions {
+            require_literal_leading_dot: true,
+            ..Default::default()
+        };
+        let paths = match glob::glob_with(&pattern, options) {
+            Ok(paths) => paths
+                .filter_map(Result::ok)
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect::<Vec<String>>(),
+            Err(e) => {
+                error!("Invalid pattern {}: {}", pattern, e);
+                let msg = format!("{}: {}", pattern, e);
+                let err = RemoteError::new(RemoteErrorKind::InvalidRequest, &msg);
+                return send_error(endpoint, &err).await;
+            }
+        };
+
+        if paths.is_empty() {
+            let err = RemoteError::new(RemoteErrorKind::NotFound, &pattern);
+            return send_error(endpoint, &err).await;
+        }
+
+        send_paths(paths, endpoint).await
+    }
+
+    /// Whether the request is a download of the files matching a pattern
+    pub fn is_pattern(&self) -> bool {
+        self.method == RequestMethod::Download && is_glob(&self.source)
+    }
+
+    /// Expand a download of a pattern into a download of each file the pattern matches on the
+    /// server, other requests are left as they are. File names may contain the characters of
+    /// patterns, a pattern that is invalid or matches nothing is downloaded as a path.
+    pub async fn expand(&self, endpoint: &mut Endpoint) -> Result<Vec<Request>, HermodError> {
+        if !self.is_pattern() {
+            return Ok(vec![self.clone()]);
+        }
+        let request = Request {
+            source: self.source.clone(),
+            destination: PathBuf::new(),
+            method: RequestMethod::Glob,
+            filter: Filter::default(),
+            compress: false,
+        };
+        request.send_request(endpoint).await?;
+        let paths = match recv_paths(endpoint).await {
+            Ok(paths) => paths,
+            Err(e) if matches!(e.kind(), HermodErrorKind::Remote(_)) => {
+                let stat = Request::file(&self.source.to_string_lossy(), "", RequestMethod::Stat)?;
+                return match stat.stat(endpoint).await {
+                    Ok(_) => Ok(vec![self.clone()]),
+                    Err(_) => Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        };
+
+        println!(
+            "About to retrive {} matches for {}",
+            paths.len(),
+            self.source.display()
+        );
+
+        // Keep the directory structure below the part of the pattern without wildcards
+        let base: PathBuf = self
+            .source
+            .components()
+            .take_while(|component| !is_glob(component))
+            .collect();
+
+        let mut requests = Vec::new();
+        for path in paths {
+            let mut destination = self.destination.clone();
+            if let Ok(relative) = std::path::Path::new(&path).strip_prefix(&base) {
+                if let Some(parent) = relative.parent() {
+                    destination.push(parent);
+                }
+            }
+            let request = Request::file(&path, &destination.to_string_lossy(), self.method)?
+                .with_filter(&self.filter)
+                .with_compression(self.compress);
+            requests.push(request);
+        }
+        Ok(requests)
+    }
+
+    /// Send a `Stat` request and return the information about the remote path
+    pub async fn stat(&self, endpoint: &mut Endpoint) -> Result<FileStat, HermodError> {
+        self.send_request(endpoint).await?;
+        self.recv_stat(endpoint).await
+    }
+
+    /// Send a `List` request and return the entries of the remote directory
+    pub async fn list(&self, endpoint: &mut Endpoint) -> Result<Vec<FileStat>, HermodError> {
+        self.send_request(endpoint).await?;
+        self.recv_list(endpoint).await
+    }
+
+    async fn recv_stat(&self, endpoint: &mut Endpoint) -> Result<FileStat, HermodError> {
+        let msg = endpoint.recv().await?;
+        match msg.get_type() {
+            MessageType::Metadata => bincode::deserialize(msg.get_payload())
+                .map_err(|_| HermodError::new(HermodErrorKind::UnknownMessage)),
+            MessageType::Error => Err(remote_error(&msg)),
+            _ => Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+        }
+    }
+
+    async fn recv_list(&self, endpoint: &mut Endpoint) -> Result<Vec<FileStat>, HermodError> {
+        let mut entries = Vec::new();
+        loop {
+            let msg = endpoint.recv().await?;
+            match msg.get_type() {
+                MessageType::Payload => {
+                    let mut chunk: Vec<FileStat> = bincode::deserialize(msg.get_payload())
+                        .map_err(|_| HermodError::new(HermodErrorKind::UnknownMessage))?;
+                    entries.append(&mut chunk);
+                }
+                MessageType::EOF => break,
+                MessageType::Error => return Err(remote_error(&msg)),
+                _ => return Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+            }
         }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
     }
 
     async fn upload_server(&self, endpoint: &mut Endpoint) -> Result<(), HermodError> {
-        let path = self.source.canonicalize()?;
+        let path = match self.source.canonicalize() {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Failed to {}: {}", self, e);
+                return send_error(endpoint, &RemoteError::from_io(&e, &self.source)).await;
+            }
+        };
         let (tx, rx) = async_std::sync::channel(100);
 
         if path.as_path().is_dir() {
             let metadata = Metadata::from_path(&path).await?;
             send_metadata(&metadata, endpoint).await?;
-            send_dir_content(async_std::path::PathBuf::from(path), endpoint).await?;
+            send_dir_content(path, &self.filter, endpoint).await?;
         } else {
             let file = File::open(&path).await?;
             let buf_reader = BufReader::new(file);
@@ -276,9 +659,10 @@ impl Request {
 
             // Spawns a task that reads a file and sends it to a receiver, responisble for sending the
             // messages to the endpoint/peer
-            async_std::task::spawn(
-                async move { read_file(buf_reader, tx, &metadata, false).await },
-            );
+            let compress = self.compress;
+            async_std::task::spawn(async move {
+                read_file(buf_reader, tx, &metadata, false, compress).await
+            });
 
             while let Ok(msg) = rx.recv().await {
                 endpoint.send(&msg).await?;
@@ -299,7 +683,10 @@ impl Request {
 
         // Spawns a task that reads a file and sends it to a receiver, responisble for sending the
         // messages to the endpoint/peer
-        async_std::task::spawn(async move { read_file(buf_reader, tx, &metadata, true).await });
+        let compress = self.compress;
+        async_std::task::spawn(async move {
+            read_file(buf_reader, tx, &metadata, true, compress).await
+        });
 
         while let Ok(msg) = rx.recv().await {
             endpoint.send(&msg).await?;
@@ -333,7 +720,7 @@ impl Request {
                 break;
             }
 
-            tx.send(msg).await;
+            tx.send(self.decompress(msg)?).await;
         }
 
         Ok(())
@@ -344,7 +731,7 @@ impl Request {
         let msg = endpoint.recv().await?;
 
         if msg.get_type() == MessageType::Error {
-            return Err(HermodError::new(HermodErrorKind::Other));
+            return Err(remote_error(&msg));
         }
 
         let metadata: Metadata = bincode::deserialize(msg.get_payload()).unwrap();
@@ -366,16 +753,7 @@ impl Request {
         endpoint: &mut Endpoint,
         metadata: &Metadata,
     ) -> Result<(), HermodError> {
-        let mut paths = PathList::new();
-        loop {
-            let msg = endpoint.recv().await?;
-            if msg.get_type() == MessageType::EOF {
-                break;
-            } else if msg.get_type() == MessageType::Error {
-                return Err(HermodError::new(HermodErrorKind::Other));
-            }
-            paths.append(&mut bincode::deserialize::<Vec<String>>(msg.get_payload()).unwrap());
-        }
+        let paths = recv_paths(endpoint).await?;
 
         println!(
             "About to retrive {} files from {:#?}",
@@ -404,7 +782,8 @@ impl Request {
 {}",
                         path
                     )
-                });
+                })
+                .with_compression(self.compress);
             request.get_file(endpoint).await?;
         }
 
@@ -418,7 +797,7 @@ impl Request {
         let msg = endpoint.recv().await?;
 
         if msg.get_type() == MessageType::Error {
-            return Err(HermodError::new(HermodErrorKind::Other));
+            return Err(remote_error(&msg));
         }
 
         let metadata: Metadata = bincode::deserialize(msg.get_payload()).unwrap();
@@ -451,7 +830,8 @@ impl Request {
         let pb = create_progress_bar(&metadata, "Downloading");
 
         // Spawn a task that write the incoming payload to disk
-        async_std::task::spawn(async move { write_file(buf_writer, rx, path.as_path()).await });
+        let writer =
+            async_std::task::spawn(async move { write_file(buf_writer, rx, path.as_path()).await });
 
         // Recv messages until an Error or Close message has been received
         let mut received = 0u64;
@@ -471,46 +851,105 @@ impl Request {
                 break;
             }
 
+            let msg = self.decompress(msg)?;
             received += msg.get_payload().len() as u64;
             pb.set_position(received);
 
             tx.send(msg).await;
         }
 
+        // Make sure the file is written before the next request, or before the client exits
+        writer.await;
         Ok(())
     }
+
+    /// Restore the content of a payload message sent with compression enabled, a payload may
+    /// not inflate to more than a message holds
+    fn decompress(&self, msg: Message) -> Result<Message, HermodError> {
+        if !self.compress || msg.get_type() != MessageType::Payload {
+            return Ok(msg);
+        }
+        let mut payload = Vec::with_capacity(MSG_PAYLOAD_LEN);
+        let decoder = DeflateDecoder::new(msg.get_payload());
+        let mut decoder = std::io::Read::take(decoder, MSG_PAYLOAD_LEN as u64 + 1);
+        std::io::Read::read_to_end(&mut decoder, &mut payload)?;
+        if payload.len() > MSG_PAYLOAD_LEN {
+            return Err(HermodError::new(HermodErrorKind::UnknownMessage));
+        }
+        Ok(Message::new(MessageType::Payload, &payload))
+    }
 }
 
-fn read_dir(
-    path: async_std::path::PathBuf,
-) -> impl Stream<Item = Result<async_std::path::PathBuf, HermodError>> {
-    async fn read_dir_internal(
-        path: async_std::path::PathBuf,
-        to_visit: &mut Vec<async_std::path::PathBuf>,
-    ) -> Result<Vec<async_std::path::PathBuf>, HermodError> {
-        let mut dir = fs::read_dir(path).await?;
-        let mut files = Vec::new();
-
-        while let Some(entry) = dir.next().await {
-            let entry = entry?;
-            if entry.metadata().await?.is_dir() {
-                to_visit.push(entry.path());
-            } else {
-                files.push(entry.path());
-            }
+async fn list_path(path: &PathBuf) -> Result<Vec<FileStat>, std::io::Error> {
+    let stat = FileStat::from_path(path).await?;
+    if !stat.dir {
+        return Ok(vec![stat]);
+    }
+
+    let mut entries = Vec::new();
+    let mut dir = fs::read_dir(path).await?;
+    while let Some(entry) = dir.next().await {
+        let entry = entry?;
+        entries.push(FileStat::from_path(&PathBuf::from(entry.path().into_os_string())).await?);
+    }
+    Ok(entries)
+}
+
+async fn remove_path(path: &PathBuf, recursive: bool) -> Result<(), std::io::Error> {
+    let metadata = fs::symlink_metadata(path).await?;
+    if !metadata.is_dir() {
+        fs::remove_file(path).await
+    } else if recursive {
+        fs::remove_dir_all(path).await
+    } else {
+        fs::remove_dir(path).await
+    }
+}
+
+/// Wait for the peer to acknowledge a request that carries no payload in its response
+async fn recv_status(endpoint: &mut Endpoint) -> Result<(), HermodError> {
+    let msg = endpoint.recv().await?;
+    match msg.get_type() {
+        MessageType::Okay => Ok(()),
+        MessageType::Error => Err(remote_error(&msg)),
+        _ => Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+    }
+}
+
+async fn recv_paths(endpoint: &mut Endpoint) -> Result<PathList, HermodError> {
+    let mut paths = PathList::new();
+    loop {
+        let msg = endpoint.recv().await?;
+        match msg.get_type() {
+            MessageType::EOF => break,
+            MessageType::Error => return Err(remote_error(&msg)),
+            _ => paths.append(
+                &mut bincode::deserialize::<Vec<String>>(msg.get_payload())
+                    .map_err(|_| HermodError::new(HermodErrorKind::UnknownMessage))?,
+            ),
         }
-        Ok(files)
     }
+    Ok(paths)
+}
 
-    stream::unfold(vec![path], |mut to_visit| async {
-        let path = to_visit.pop()?;
-        let file_stream = match read_dir_internal(path, &mut to_visit).await {
-            Ok(files) => stream::iter(files).map(Ok).left_stream(),
-            Err(e) => stream::once(async { Err(e) }).right_stream(),
-        };
-        Some((file_stream, to_visit))
-    })
-    .flatten()
+fn is_glob<P: AsRef<std::path::Path>>(path: P) -> bool {
+    path.as_ref()
+        .to_string_lossy()
+        .contains(&['*', '?', '['][..])
+}
+
+async fn send_error(endpoint: &mut Endpoint, err: &RemoteError) -> Result<(), HermodError> {
+    let enc_err = bincode::serialize(err).unwrap();
+    endpoint
+        .send(&Message::new(MessageType::Error, &enc_err))
+        .await
+}
+
+pub fn remote_error(msg: &Message) -> HermodError {
+    match bincode::deserialize::<RemoteError>(msg.get_payload()) {
+        Ok(err) => HermodError::new(HermodErrorKind::Remote(err)),
+        Err(_) => HermodError::new(HermodErrorKind::Other),
+    }
 }
 
 async fn read_file(
@@ -518,6 +957,7 @@ async fn read_file(
     tx: Sender<Message>,
     metadata: &Metadata,
     is_client: bool,
+    compress: bool,
 ) {
     let pb = if is_client {
         Some(create_progress_bar(metadata, "Uploading"))
@@ -525,12 +965,18 @@ async fn read_file(
         None
     };
 
+    let chunk_len = if compress {
+        MSG_COMPRESSED_CHUNK_LEN
+    } else {
+        MSG_PAYLOAD_LEN
+    };
+
     let mut read = 0u64;
     loop {
-        let mut buffer = Vec::with_capacity(MSG_PAYLOAD_LEN);
+        let mut buffer = Vec::with_capacity(chunk_len);
         let n = reader
             .by_ref()
-            .take(MSG_PAYLOAD_LEN as u64)
+            .take(chunk_len as u64)
             .read_to_end(&mut buffer)
             .await
             .expect("Failed to read from the file");
@@ -547,6 +993,9 @@ async fn read_file(
             tx.send(msg).await;
             break;
         }
+        if compress {
+            buffer = deflate(&buffer).expect("Failed to compress the file content");
+        }
         let msg = Message::new(MessageType::Payload, &buffer);
         tx.send(msg).await;
     }
@@ -557,6 +1006,12 @@ async fn read_file(
     }
 }
 
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len()), Compression::fast());
+    std::io::Write::write_all(&mut encoder, data)?;
+    encoder.finish()
+}
+
 async fn write_file(mut writer: BufWriter<File>, rx: Receiver<Message>, path: &Path) {
     while let Ok(msg) = rx.recv().await {
         match msg.get_type() {
@@ -605,14 +1060,30 @@ async fn send_metadata(metadata: &Metadata, endpoint: &mut Endpoint) -> Result<(
 }
 
 async fn send_dir_content(
-    path: async_std::path::PathBuf,
+    path: PathBuf,
+    filter: &Filter,
     endpoint: &mut Endpoint,
 ) -> Result<(), HermodError> {
-    let paths = read_dir(path)
-        .filter_map(|p| async { p.ok() })
-        .collect::<Vec<async_std::path::PathBuf>>()
-        .await;
-    let paths = PathList::from(paths.as_slice()).into_iter();
+    let filter = filter.clone();
+    let paths = match async_std::task::spawn_blocking(move || filter.walk(&path)).await {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!("Failed to list directory content: {}", e);
+            let err = RemoteError::new(RemoteErrorKind::InvalidRequest, &e.to_string());
+            return send_error(endpoint, &err).await;
+        }
+    };
+    let paths = paths
+        .into_iter()
+        .map(|path| path.to_string_lossy().into_owned());
+    send_paths(paths, endpoint).await
+}
+
+/// Send a list of paths, split over as many messages as needed, followed by an EOF
+async fn send_paths<P>(paths: P, endpoint: &mut Endpoint) -> Result<(), HermodError>
+where
+    P: IntoIterator<Item = String>,
+{
     let mut payload = Vec::new();
     let mut len = 0;
 
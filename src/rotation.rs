@@ -0,0 +1,173 @@
+use crate::consts::*;
+use crate::error::{HermodError, HermodErrorKind};
+use crate::permissions;
+use crate::secret::SecretKey;
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use rand_core::OsRng;
+
+use serde::{Deserialize, Serialize};
+
+use xeddsa::xed25519::{PrivateKey, PublicKey};
+use xeddsa::{Sign, Verify};
+
+use zeroize::Zeroizing;
+
+/// Version of the key rotation format, both on disk and when announced to clients
+const KEY_ROTATION_VERSION: u32 = 1;
+
+/// Announcement of a new server key, signed by the key it replaces.
+/// The server keeps accepting the previous key until the announcement expires, and sends the
+/// announcement to the clients that still use it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRotation {
+    version: u32,
+    previous_key: String,
+    public_key: String,
+    expires: String,
+    signature: String,
+}
+
+impl KeyRotation {
+    /// Sign the replacement of `previous_key` by `public_key` with the previous private key.
+    /// X25519 keys can not sign by themselves, the signature is made with XEdDSA.
+    pub fn new(
+        previous_private_key: &SecretKey,
+        previous_key: &[u8],
+        public_key: &[u8],
+        expires: DateTime<Utc>,
+    ) -> Result<Self, HermodError> {
+        let private_key: Zeroizing<[u8; 32]> = Zeroizing::new(
+            previous_private_key
+                .as_bytes()
+                .try_into()
+                .map_err(|_| error("invalid length of the private key"))?,
+        );
+        let mut rotation = KeyRotation {
+            version: KEY_ROTATION_VERSION,
+            previous_key: base64::encode(previous_key),
+            public_key: base64::encode(public_key),
+            expires: expires.to_rfc3339_opts(SecondsFormat::Secs, true),
+            signature: String::new(),
+        };
+        let signature: [u8; 64] = PrivateKey(*private_key).sign(&rotation.message(), OsRng);
+        rotation.signature = base64::encode(&signature[..]);
+        Ok(rotation)
+    }
+
+    /// Check that the announcement replaces `pinned_key` and is signed by it
+    pub fn verify(&self, pinned_key: &[u8]) -> Result<(), HermodError> {
+        if self.version != KEY_ROTATION_VERSION {
+            return Err(error(&format!("unsupported version {}", self.version)));
+        }
+        let previous_key = base64::decode(&self.previous_key)?;
+        if previous_key != pinned_key {
+            return Err(error("the announcement is for another server key"));
+        }
+        let previous_key: [u8; 32] = previous_key
+            .try_into()
+            .map_err(|_| error("invalid length of the previous key"))?;
+        let signature: [u8; 64] = base64::decode(&self.signature)?
+            .try_into()
+            .map_err(|_| error("invalid length of the signature"))?;
+        PublicKey(previous_key)
+            .verify(&self.message(), &signature)
+            .map_err(|_| error("invalid signature"))?;
+        if self.public_key()?.len() != 32 {
+            return Err(error("invalid length of the new key"));
+        }
+        Ok(())
+    }
+
+    /// The new public key of the server
+    pub fn public_key(&self) -> Result<Vec<u8>, HermodError> {
+        Ok(base64::decode(&self.public_key)?)
+    }
+
+    /// The end of the grace period, after which the previous key is no longer accepted.
+    /// An unreadable time is treated as already passed.
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.expires)
+            .ok()
+            .map(|expires| expires.with_timezone(&Utc))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires().is_none_or(|expires| expires <= Utc::now())
+    }
+
+    /// The signed content, every field but the signature
+    fn message(&self) -> Vec<u8> {
+        let mut message = b"hermod-key-rotation".to_vec();
+        message.extend(&self.version.to_be_bytes());
+        for field in &[&self.previous_key, &self.public_key, &self.expires] {
+            message.push(0);
+            message.extend(field.as_bytes());
+        }
+        message
+    }
+}
+
+/// Path of the key rotation file, present while the previous server key is still accepted
+pub fn rotation_path() -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(dirs::home_dir().expect("Failed to get home directory path"));
+    path.push(HERMOD_BASE_DIR);
+    path.push(HERMOD_KEY_ROTATION_FILE);
+    path
+}
+
+/// Read the latest key rotation, if the server key has ever been rotated
+pub fn read_rotation() -> Result<Option<KeyRotation>, HermodError> {
+    let path = rotation_path();
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    toml::from_str(&content)
+        .map(Some)
+        .map_err(|e| error(&format!("{}: {}", path.display(), e)))
+}
+
+pub fn write_rotation(rotation: &KeyRotation) -> Result<(), HermodError> {
+    let content = toml::to_string(rotation).map_err(|e| error(&e.to_string()))?;
+    let path = rotation_path();
+    if let Some(dir) = path.parent() {
+        permissions::create_private_dir(dir)?;
+    }
+    permissions::write_private_file(&path, content.as_bytes())?;
+    Ok(())
+}
+
+fn error(msg: &str) -> HermodError {
+    HermodError::new(HermodErrorKind::KeyRotation(msg.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genkey;
+
+    #[test]
+    fn verify_signed_rotation() {
+        let previous = genkey::create_server_keys().unwrap();
+        let new = genkey::create_server_keys().unwrap();
+        let expires = Utc::now() + chrono::Duration::days(1);
+        let rotation =
+            KeyRotation::new(&previous.private, &previous.public, &new.public, expires).unwrap();
+        assert!(rotation.verify(&previous.public).is_ok());
+        assert!(rotation.verify(&new.public).is_err());
+        assert!(!rotation.is_expired());
+
+        let mut forged = rotation.clone();
+        forged.public_key = base64::encode(&previous.public);
+        assert!(forged.verify(&previous.public).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::Once;
+
+use log::warn;
+
+use zeroize::Zeroize;
+
+static MLOCK_FAILED: Once = Once::new();
+
+/// Secret key material that is locked in memory, so that it is not swapped to disk,
+/// and wiped when dropped.
+///
+/// Every key gets its own page aligned allocation, memory is locked by page and unlocking
+/// the key must not unlock memory used by anything else.
+pub struct SecretKey {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SecretKey owns its allocation, like a Box<[u8]>
+unsafe impl Send for SecretKey {}
+unsafe impl Sync for SecretKey {}
+
+impl SecretKey {
+    pub fn new(key: &[u8]) -> Self {
+        let layout = layout(key.len());
+        // Safety: the layout has a non-zero size
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        // Safety: the allocation is at least key.len() bytes
+        let locked = unsafe {
+            ptr::copy_nonoverlapping(key.as_ptr(), ptr.as_ptr(), key.len());
+            libc::mlock(ptr.as_ptr() as *const libc::c_void, layout.size()) == 0
+        };
+        // Locking fails when RLIMIT_MEMLOCK is reached, the key is still wiped on drop
+        if !locked {
+            let err = std::io::Error::last_os_error();
+            MLOCK_FAILED.call_once(|| {
+                warn!(
+                    "Failed to lock keys in memory, they may be swapped to disk: {}",
+                    err
+                )
+            });
+        }
+        SecretKey {
+            ptr,
+            len: key.len(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        // Safety: ptr points to an allocation of at least len initialized bytes
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        let layout = layout(self.len);
+        unsafe {
+            slice::from_raw_parts_mut(self.ptr.as_ptr(), layout.size()).zeroize();
+            libc::munlock(self.ptr.as_ptr() as *const libc::c_void, layout.size());
+            alloc::dealloc(self.ptr.as_ptr(), layout);
+        }
+    }
+}
+
+impl Default for SecretKey {
+    fn default() -> Self {
+        SecretKey::new(&[])
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        SecretKey::new(self.as_bytes())
+    }
+}
+
+impl AsRef<[u8]> for SecretKey {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl From<&[u8]> for SecretKey {
+    fn from(key: &[u8]) -> Self {
+        SecretKey::new(key)
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey(<redacted>)")
+    }
+}
+
+/// Whole pages, so that locking the key never locks, or unlocks, anything else
+fn layout(len: usize) -> Layout {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let size = len.max(1).div_ceil(page_size) * page_size;
+    Layout::from_size_align(size, page_size).expect("Invalid layout for a secret key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_key_is_redacted() {
+        let key = SecretKey::new(&[1u8; 32]);
+        assert_eq!(key.clone().as_bytes(), &[1u8; 32]);
+        assert_eq!(format!("{:?}", key), "SecretKey(<redacted>)");
+        assert!(SecretKey::default().is_empty());
+    }
+}
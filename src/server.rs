@@ -1,19 +1,28 @@
+use crate::certificate::Certificate;
+use crate::config::{ServerSettings, SERVER_CONFIG, SERVER_SETTINGS};
 use crate::consts::*;
-use crate::error::HermodError;
+use crate::cookie::{CookieSecret, HandshakeLimiter};
+use crate::error::{HermodError, HermodErrorKind, RemoteError, RemoteErrorKind};
 use crate::genkey;
 use crate::identity;
 use crate::message::{Message, MessageType};
+use crate::noise::IdentityHandshake;
+use crate::passphrase;
 use crate::peer::Endpoint;
 use crate::peer::Peer;
+use crate::permissions;
 use crate::request::Request;
+use crate::rotation::{self, KeyRotation};
 use crate::share_key;
 
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::prelude::*;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::Path;
 use std::str;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use async_std::io;
 use async_std::net::{TcpListener, TcpStream};
@@ -22,23 +31,93 @@ use async_std::task;
 
 use async_listen::{backpressure::Token, error_hint, ListenExt};
 
+use chrono::{DateTime, Utc};
+
+use futures::channel::oneshot;
+use futures::{future, stream};
+
+use lazy_static::lazy_static;
+
 use log::{debug, error, info, warn};
 
+use zeroize::Zeroizing;
+
+lazy_static! {
+    /// Active sessions by session number, with the id of the client, the end of its certificate
+    /// if it was admitted by one and a way to end the session
+    static ref SESSIONS: Mutex<HashMap<u64, SessionEntry>> = Mutex::new(HashMap::new());
+    static ref COOKIES: Mutex<CookieSecret> = Mutex::new(CookieSecret::new());
+    static ref HANDSHAKE_LIMITER: Mutex<HandshakeLimiter> = Mutex::new(HandshakeLimiter::new());
+}
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKES: AtomicUsize = AtomicUsize::new(0);
+
 pub struct HermodServer {}
 
+type SessionEntry = (String, Option<DateTime<Utc>>, oneshot::Sender<()>);
+
+/// Registration of an active session, removed from the registry when dropped
+struct Session {
+    number: u64,
+}
+
+impl Session {
+    /// Register a session for `id`, the receiver completes if the access of `id` is revoked.
+    /// The session of a client admitted by a certificate ends at `valid_before` instead.
+    fn register(id: &str, valid_before: Option<DateTime<Utc>>) -> (Session, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        let number = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+        SESSIONS
+            .lock()
+            .unwrap()
+            .insert(number, (id.to_owned(), valid_before, tx));
+        (Session { number }, rx)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        SESSIONS.lock().unwrap().remove(&self.number);
+    }
+}
+
+/// A handshake in progress, counted until dropped
+struct Handshake {
+    in_progress: usize,
+}
+
+impl Handshake {
+    fn start() -> Handshake {
+        let in_progress = HANDSHAKES.fetch_add(1, Ordering::Relaxed) + 1;
+        Handshake { in_progress }
+    }
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        HANDSHAKES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 impl<'hs> HermodServer {
-    pub fn run_server(ip: SocketAddr) {
+    pub fn run_server(settings: &ServerSettings) {
         async_std::task::block_on(async {
-            let listener: TcpListener = TcpListener::bind(ip).await.unwrap();
-            info!("Listening on {}", listener.local_addr().unwrap());
+            let mut listeners = Vec::new();
+            for addr in settings.addresses() {
+                let listener: TcpListener = TcpListener::bind(addr).await.unwrap();
+                info!("Listening on {}", listener.local_addr().unwrap());
+                listeners.push(listener);
+            }
 
-            let mut incoming = listener
-                .incoming()
+            let mut incoming = stream::select_all(listeners.iter().map(|l| l.incoming()))
                 .log_warnings(|e| {
                     warn!("Accept error: {}. Sleeping 0.5s. {}", e, error_hint(&e));
                 })
                 .handle_errors(Duration::from_millis(500))
-                .backpressure(100);
+                .backpressure(settings.max_connections);
+
+            task::spawn(watch_clients());
 
             while let Some((token, mut stream)) = incoming.next().await {
                 task::spawn(async move {
@@ -54,16 +133,9 @@ impl<'hs> HermodServer {
         });
     }
 
-    pub fn setup(force: bool) {
-        let exists = |filepath: &str| -> bool {
-            let mut path = PathBuf::new();
-            path.push(dirs::home_dir().expect("Failed to get home directory"));
-            path.push(HERMOD_BASE_DIR);
-            path.push(filepath);
-            path.as_path().exists()
-        };
-
-        let exists = exists(SERVER_PRIVATE_KEY_FILE) || exists(SERVER_PUBLIC_KEY_FILE);
+    pub fn setup(force: bool, passphrase: Option<&str>) {
+        let settings = SERVER_SETTINGS.read().unwrap().clone();
+        let exists = settings.private_key.exists() || settings.public_key.exists();
 
         if exists && !force {
             eprintln!("Previous configuration found, pass --force to overwrite");
@@ -75,21 +147,100 @@ impl<'hs> HermodServer {
         let keys =
             genkey::create_server_keys().expect("Failed to crate static keys for the server");
 
-        let write_key = |key: &[u8], filepath: &str| -> io::Result<()> {
-            let mut path = PathBuf::new();
-            path.push(dirs::home_dir().expect("Failed to get home directory"));
-            path.push(HERMOD_BASE_DIR);
-            fs::create_dir_all(&path).expect("Failed to create hermod directory directory");
-            path.push(filepath);
+        let write_key = |key: &[u8], path: &Path| -> io::Result<()> {
+            if let Some(dir) = path.parent() {
+                permissions::create_private_dir(dir).expect("Failed to create the key directory");
+            }
             let mut file = File::create(path)?;
-            file.write_all(base64::encode(key).as_bytes())?;
+            file.write_all(key)?;
             Ok(())
         };
 
-        write_key(&keys.private, SERVER_PRIVATE_KEY_FILE)
+        let private_key = match passphrase {
+            Some(passphrase) => passphrase::encrypt(keys.private.as_bytes(), passphrase)
+                .expect("Failed to encrypt the private key"),
+            None => base64::encode(&keys.private),
+        };
+        let private_key = Zeroizing::new(private_key);
+        if let Some(dir) = settings.private_key.parent() {
+            permissions::create_private_dir(dir).expect("Failed to create the key directory");
+        }
+        permissions::write_private_file(&settings.private_key, private_key.as_bytes())
             .expect("Failed to write the private key to file");
-        write_key(&keys.public, SERVER_PUBLIC_KEY_FILE)
-            .expect("Failed to write the public key to file");
+        write_key(
+            base64::encode(&keys.public).as_bytes(),
+            &settings.public_key,
+        )
+        .expect("Failed to write the public key to file");
+    }
+
+    /// Add, change or remove the passphrase protecting the private key of the server
+    pub fn set_passphrase(passphrase: Option<&str>) -> Result<(), HermodError> {
+        let settings = SERVER_SETTINGS.read().unwrap().clone();
+        let key = settings.unlock_private_key()?;
+        let content = match passphrase {
+            Some(passphrase) => passphrase::encrypt(key.as_bytes(), passphrase)?,
+            None => base64::encode(&key),
+        };
+        let content = Zeroizing::new(content);
+        permissions::write_private_file(&settings.private_key, content.as_bytes())?;
+        Ok(())
+    }
+
+    /// Replace the static key of the server. The previous key is accepted for `grace`, clients
+    /// that connect with it in the meantime are sent the new key signed by the previous one.
+    pub fn rotate_key(grace: chrono::Duration, force: bool) -> Result<(), HermodError> {
+        let settings = SERVER_SETTINGS.read().unwrap().clone();
+        if let Some(rotation) = rotation::read_rotation()?.filter(|r| !r.is_expired()) {
+            if !force {
+                let expires = rotation.expires().unwrap().with_timezone(&chrono::Local);
+                return Err(HermodError::new(HermodErrorKind::KeyRotation(format!(
+                    "the previous rotation is in progress until {}, pass --force to replace the key anyway",
+                    expires.format("%Y-%m-%d %H:%M")
+                ))));
+            }
+        }
+
+        let mut passphrase = None;
+        let previous = settings.unlock_key(&settings.private_key, &mut passphrase)?;
+        let previous_public = fs::read_to_string(&settings.public_key)
+            .map_err(|e| HermodError::new(HermodErrorKind::ReadPubKey(e)))?;
+        let previous_public = base64::decode(previous_public.trim())?;
+        let keys = genkey::create_server_keys()?;
+        let rotation = KeyRotation::new(
+            &previous,
+            &previous_public,
+            &keys.public,
+            chrono::Utc::now() + grace,
+        )?;
+
+        // The previous key is kept as is, protected by the same passphrase if it has one
+        let content = Zeroizing::new(fs::read(&settings.private_key)?);
+        permissions::write_private_file(&settings.previous_private_key(), &content)?;
+
+        // Each file is replaced at once, and the rotation is only announced once the new keys
+        // are in place
+        let private_key = match passphrase {
+            Some(ref passphrase) => passphrase::encrypt(keys.private.as_bytes(), passphrase)?,
+            None => base64::encode(&keys.private),
+        };
+        let private_key = Zeroizing::new(private_key);
+        permissions::write_private_file(&settings.private_key, private_key.as_bytes())?;
+        permissions::write_public_file(
+            &settings.public_key,
+            base64::encode(&keys.public).as_bytes(),
+        )?;
+        rotation::write_rotation(&rotation)?;
+        Ok(())
+    }
+
+    pub fn print_fingerprint() -> Result<(), HermodError> {
+        let settings = SERVER_SETTINGS.read().unwrap().clone();
+        let content = fs::read_to_string(&settings.public_key)
+            .map_err(|e| HermodError::new(HermodErrorKind::ReadPubKey(e)))?;
+        let key = base64::decode(content.trim())?;
+        println!("{}", genkey::fingerprint(&key));
+        Ok(())
     }
 
     pub fn list_known_clients() {
@@ -97,19 +248,115 @@ impl<'hs> HermodServer {
     }
 }
 
+/// Reload the authorized clients on SIGHUP or when the file changes,
+/// ending the sessions of clients that are no longer authorized
+async fn watch_clients() {
+    let hangup = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, hangup.clone()) {
+        error!("Failed to listen for SIGHUP: {}", e);
+    }
+
+    let path = identity::clients_path();
+    let modified = || -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(&path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    };
+
+    let mut last_modified = modified();
+    loop {
+        task::sleep(Duration::from_secs(HERMOD_CLIENTS_POLL_INTERVAL)).await;
+
+        // The access of a client may end while its sessions are open
+        let now = Utc::now();
+        let ids: Vec<String> = SESSIONS
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(_, valid_before, _)| valid_before.is_none())
+            .map(|(id, _, _)| id.clone())
+            .collect();
+        if !ids.is_empty() {
+            revoke_sessions(&identity::without_access(&ids, now).await);
+        }
+        end_sessions(|_, valid_before| valid_before.is_some_and(|before| before <= now));
+
+        let current = modified();
+        if !hangup.swap(false, Ordering::Relaxed) && current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match identity::reload_clients().await {
+            Ok(revoked) => {
+                info!("Reloaded the authorized clients");
+                revoke_sessions(&revoked);
+            }
+            Err(e) => error!("Failed to reload the authorized clients: {}", e),
+        }
+    }
+}
+
+/// End the sessions of the clients `ids` from authorized_clients
+fn revoke_sessions(ids: &[String]) {
+    end_sessions(|id, valid_before| {
+        valid_before.is_none() && ids.iter().any(|revoked| revoked == id)
+    });
+}
+
+/// End the sessions `ended` returns true for, given the id of the client and the end of its
+/// certificate
+fn end_sessions(ended: impl Fn(&str, Option<DateTime<Utc>>) -> bool) {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let revoked: Vec<u64> = sessions
+        .iter()
+        .filter(|(_, (id, valid_before, _))| ended(id, *valid_before))
+        .map(|(number, _)| *number)
+        .collect();
+
+    for number in revoked {
+        if let Some((id, _, tx)) = sessions.remove(&number) {
+            info!("Ending session of revoked client {}", id);
+            let _ = tx.send(());
+        }
+    }
+}
+
 async fn handle_connection(_token: &Token, stream: &mut TcpStream) -> Result<(), HermodError> {
     // log incomming packet from ip
 
+    // A client that stalls before or during its handshake would hold the connection
+    let timeout = Duration::from_secs(HERMOD_HANDSHAKE_TIMEOUT);
     let mut msg_type = vec![0u8];
-    stream.read_exact(&mut msg_type).await?;
+    io::timeout(timeout, stream.read_exact(&mut msg_type)).await?;
     debug!(
         "Incomming message of type: {}, value: {:?}",
         MessageType::from(msg_type[0]),
         msg_type
     );
+    // A client the server asked for a cookie presents it before its handshake
+    let cookie = if MessageType::from(msg_type[0]) == MessageType::Cookie {
+        let mut cookie = [0u8; COOKIE_LEN];
+        io::timeout(timeout, async {
+            stream.read_exact(&mut cookie).await?;
+            stream.read_exact(&mut msg_type).await
+        })
+        .await?;
+        Some(cookie)
+    } else {
+        None
+    };
     match MessageType::from(msg_type[0]) {
-        MessageType::Init => incomming_request(stream).await,
-        MessageType::ShareKeyInit => share_key(stream).await,
+        MessageType::Init => match admit(stream, cookie).await? {
+            Some(handshake) => incomming_request(stream, handshake).await,
+            None => Ok(()),
+        },
+        MessageType::IdentityInit => match admit(stream, cookie).await? {
+            Some(handshake) => identity_request(stream, handshake).await,
+            None => Ok(()),
+        },
+        // Enrollment touches the invites and the clients files, it is limited like handshakes
+        MessageType::ShareKeyInit if within_rate(stream)? => share_key(stream).await,
+        MessageType::PairInit if within_rate(stream)? => pair(stream).await,
         _ => Ok(()),
     }
 }
@@ -117,29 +364,218 @@ async fn handle_connection(_token: &Token, stream: &mut TcpStream) -> Result<(),
 async fn share_key(stream: &mut TcpStream) -> Result<(), HermodError> {
     debug!("Sharing key with client");
     let mut buffer = vec![0u8; HERMOD_KS_INIT_LEN];
-    stream.read_exact(&mut buffer).await?;
+    let timeout = Duration::from_secs(HERMOD_HANDSHAKE_TIMEOUT);
+    io::timeout(timeout, stream.read_exact(&mut buffer)).await?;
     let msg = Message::new(MessageType::ShareKeyInit, &buffer);
     share_key::receive_key(stream, &msg).await?;
     debug!("Shared key with client");
     Ok(())
 }
 
-async fn incomming_request(stream: &mut TcpStream) -> Result<(), HermodError> {
+async fn pair(stream: &mut TcpStream) -> Result<(), HermodError> {
+    debug!("Pairing with client");
+    let mut buffer = vec![0u8; HERMOD_PAIR_INIT_LEN];
+    let timeout = Duration::from_secs(HERMOD_HANDSHAKE_TIMEOUT);
+    io::timeout(timeout, stream.read_exact(&mut buffer)).await?;
+    let msg = Message::new(MessageType::PairInit, &buffer);
+    share_key::receive_pairing(stream, &msg).await?;
+    debug!("Paired with client");
+    Ok(())
+}
+
+/// Decide whether to go through with a handshake, before any Diffie-Hellman or lookup of the
+/// client. Under load a client must first present a cookie for its address, and each address
+/// may only start so many handshakes per minute.
+async fn admit(
+    stream: &mut TcpStream,
+    cookie: Option<[u8; COOKIE_LEN]>,
+) -> Result<Option<Handshake>, HermodError> {
+    let addr = stream.peer_addr()?.ip();
+    let load = SERVER_SETTINGS.read().unwrap().handshake_load;
+    if let Some(cookie) = cookie {
+        if !COOKIES.lock().unwrap().check(addr, &cookie) {
+            debug!("Dropping a handshake from {} with an invalid cookie", addr);
+            return Ok(None);
+        }
+    }
+
+    let handshake = Handshake::start();
+    if cookie.is_none() && handshake.in_progress > load {
+        debug!("Under load, sending a cookie to {}", addr);
+        let mut reply = vec![MessageType::CookieReply as u8];
+        reply.extend(&COOKIES.lock().unwrap().cookie(addr));
+        stream.write_all(&reply).await?;
+        drain(stream).await;
+        return Ok(None);
+    }
+    if !within_rate(stream)? {
+        return Ok(None);
+    }
+    Ok(Some(handshake))
+}
+
+/// Whether the peer of `stream` may start another handshake, `handshake_rate` are allowed per
+/// minute from each address
+fn within_rate(stream: &TcpStream) -> Result<bool, HermodError> {
+    let addr = stream.peer_addr()?.ip();
+    let rate = SERVER_SETTINGS.read().unwrap().handshake_rate;
+    let allowed = HANDSHAKE_LIMITER
+        .lock()
+        .unwrap()
+        .allow(addr, rate, Instant::now());
+    if !allowed {
+        debug!("Dropping a handshake from {}, too many handshakes", addr);
+    }
+    Ok(allowed)
+}
+
+/// Discard what the client sent before closing the connection, unread data would reset the
+/// connection before the client reads the answer
+async fn drain(stream: &mut TcpStream) {
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let limit = (HYBRID_KEY_LEN + HERMOD_HS_IDENTITY_INIT_LEN + HERMOD_MAX_CERTIFICATE_LEN) as u64;
+    let _ = io::timeout(
+        Duration::from_secs(1),
+        io::copy(&mut stream.take(limit), &mut io::sink()),
+    )
+    .await;
+}
+
+async fn incomming_request(
+    stream: &mut TcpStream,
+    in_progress: Handshake,
+) -> Result<(), HermodError> {
     let mut buffer = vec![0u8; HERMOD_HS_INIT_LEN - MSG_TYPE_LEN];
-    stream.read_exact(&mut buffer).await?;
+    let timeout = Duration::from_secs(HERMOD_HANDSHAKE_TIMEOUT);
+    io::timeout(timeout, stream.read_exact(&mut buffer)).await?;
 
     let msg = Message::new(MessageType::Init, &buffer);
     // 12 = tokenid base64len
-    let peer = Peer::new_client_peer(
-        &str::from_utf8(&msg.get_payload()[0..12])
-            .expect("Failed to read client id from Init message"),
-    )
-    .await?;
+    let id = str::from_utf8(&msg.get_payload()[0..12])
+        .map_err(|_| HermodError::new(HermodErrorKind::UnknownIdentity))?;
 
-    let mut endpoint = Endpoint::server(stream, peer, &msg).await?;
+    // Register before looking up the client, so a reload in between can not miss the session
+    let (session, revoked) = Session::register(id, None);
+    let peer = Peer::new_client_peer(id).await?;
 
-    // Request loop listen for and handle incomming requests
+    let endpoint = Endpoint::server(stream, peer, &msg).await?;
+    drop(in_progress);
+    serve_session(endpoint, session, revoked).await
+}
+
+/// A client sending its static key encrypted, either with a certificate signed by the
+/// certificate authority the server trusts or known by its key
+async fn identity_request(
+    stream: &mut TcpStream,
+    in_progress: Handshake,
+) -> Result<(), HermodError> {
+    // The client may be asked to start over, the whole first message is timed
+    let timeout = Duration::from_secs(HERMOD_HANDSHAKE_TIMEOUT);
+    let handshake =
+        async_std::future::timeout(timeout, IdentityHandshake::accept(&*SERVER_CONFIG, stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let client_key = handshake.remote_static().to_vec();
+    let (peer, session, revoked) = if handshake.payload().is_empty() {
+        let id = identity::find_by_key(&client_key)
+            .await
+            .ok_or_else(|| HermodError::new(HermodErrorKind::UnknownIdentity))?;
+        // Register before looking up the client again, so a reload in between can not miss the
+        // session
+        let (session, revoked) = Session::register(&id, None);
+        let peer = Peer::new_client_peer(&id).await?;
+        if peer.get_public_key() != &client_key[..] {
+            return Err(HermodError::new(HermodErrorKind::UnknownIdentity));
+        }
+        (peer, session, revoked)
+    } else {
+        let certificate: Certificate = bincode::deserialize(handshake.payload())
+            .map_err(|e| HermodError::new(HermodErrorKind::Certificate(e.to_string())))?;
+        let peer = Peer::new_certified_peer(&certificate).await?;
+        if peer.get_public_key() != &client_key[..] {
+            let msg = String::from("issued for another key");
+            return Err(HermodError::new(HermodErrorKind::Certificate(msg)));
+        }
+        info!(
+            "Client {} presented a certificate for {}",
+            peer,
+            certificate.principals().join(", ")
+        );
+        let (session, revoked) = Session::register(peer.get_id(), certificate.valid_before());
+        (peer, session, revoked)
+    };
+
+    let endpoint = Endpoint::server_identified(stream, peer, handshake).await?;
+    drop(in_progress);
+    serve_session(endpoint, session, revoked).await
+}
+
+/// Serve the requests of a client until the connection is closed or the access of the client
+/// is revoked
+async fn serve_session(
+    mut endpoint: Endpoint,
+    _session: Session,
+    revoked: oneshot::Receiver<()>,
+) -> Result<(), HermodError> {
+    info!(
+        "Session with {} uses {}{}",
+        endpoint.get_peer(),
+        endpoint.suite(),
+        if endpoint.uses_post_quantum() {
+            " and ML-KEM-768"
+        } else {
+            ""
+        }
+    );
+
+    // Tell clients that still use the previous key about the new one
+    if endpoint.uses_previous_key() {
+        if let Some(rotation) = SERVER_CONFIG.key_rotation() {
+            info!("Announcing the new server key to {}", endpoint.get_peer());
+            let payload = bincode::serialize(rotation).unwrap();
+            endpoint
+                .send(&Message::new(MessageType::KeyRotation, &payload))
+                .await?;
+        }
+    }
+
+    let revoked = async {
+        match revoked.await {
+            Ok(()) => Err(HermodError::new(HermodErrorKind::Revoked)),
+            // The session ended on its own
+            Err(_) => future::pending().await,
+        }
+    };
+
+    let busy = AtomicBool::new(false);
+    match serve_requests(&mut endpoint, &busy).race(revoked).await {
+        Err(e) if matches!(e.kind(), HermodErrorKind::Revoked) => {
+            // A request cut off while sending may have left part of a message on the stream, the
+            // client is only told between requests
+            if !busy.load(Ordering::Relaxed) {
+                let err = RemoteError::new(RemoteErrorKind::PermissionDenied, "access revoked");
+                let msg = Message::new(MessageType::Error, &bincode::serialize(&err).unwrap());
+                let _ = endpoint.send(&msg).await;
+            }
+            info!(
+                "Closing connection, the access of {} was revoked",
+                endpoint.get_peer()
+            );
+            Ok(())
+        }
+        res => {
+            info!("Closing connection");
+            res
+        }
+    }
+}
+
+/// Request loop listen for and handle incomming requests
+/// Serve requests until the client closes the connection, `busy` is set while a request is
+/// being handled
+async fn serve_requests(endpoint: &mut Endpoint, busy: &AtomicBool) -> Result<(), HermodError> {
     loop {
+        busy.store(false, Ordering::Relaxed);
         let msg = match endpoint.recv().await {
             Ok(msg) => msg,
             Err(e) => {
@@ -147,6 +583,7 @@ async fn incomming_request(stream: &mut TcpStream) -> Result<(), HermodError> {
                 break;
             }
         };
+        busy.store(true, Ordering::Relaxed);
 
         match msg.get_type() {
             MessageType::Error => {
@@ -155,8 +592,9 @@ async fn incomming_request(stream: &mut TcpStream) -> Result<(), HermodError> {
             }
             MessageType::Request => {
                 let request: Request = bincode::deserialize(msg.get_payload()).unwrap();
-                request.respond(&mut endpoint).await?
+                request.respond(endpoint).await?
             }
+            MessageType::RotateKey => rotate_client_key(endpoint, &msg).await?,
             MessageType::Close => {
                 info!("Received 'close' from the client, closing connection");
                 break;
@@ -167,6 +605,30 @@ async fn incomming_request(stream: &mut TcpStream) -> Result<(), HermodError> {
             } // log: Received message out of order {} type, Closing connection
         }
     }
-    info!("Closing connection");
     Ok(())
 }
+
+/// Replace the key of the client with the public key sent over the session, which is
+/// authenticated with the current key
+async fn rotate_client_key(endpoint: &mut Endpoint, msg: &Message) -> Result<(), HermodError> {
+    let id = endpoint.get_peer().get_id().to_owned();
+    let session_key = endpoint.remote_static().unwrap_or_default().to_vec();
+    let res = match endpoint.get_peer() {
+        Peer::Identity(peer) => {
+            identity::replace_key(peer, &session_key, msg.get_payload().to_vec()).await
+        }
+        Peer::Host(_) => Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+    };
+    let reply = match res {
+        Ok(()) => {
+            info!("Replaced the key of client {}", id);
+            Message::new(MessageType::Okay, &[])
+        }
+        Err(e) => {
+            error!("Failed to replace the key of client {}: {}", id, e);
+            let err = RemoteError::new(RemoteErrorKind::InvalidRequest, &e.to_string());
+            Message::new(MessageType::Error, &bincode::serialize(&err).unwrap())
+        }
+    };
+    endpoint.send(&reply).await
+}
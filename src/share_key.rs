@@ -1,10 +1,10 @@
-use crate::config::{Config, SERVER_CONFIG};
+use crate::config::{Config, Enrollment, SERVER_CONFIG, SERVER_SETTINGS};
 use crate::consts::*;
 use crate::error::{HermodError, HermodErrorKind};
-use crate::genkey;
+use crate::genkey::{self, KeyPair};
 use crate::host::Host;
-use crate::identity;
-use crate::identity::KNOWN_CLIENTS;
+use crate::identity::{self, Identity, KNOWN_CLIENTS};
+use crate::invite;
 use crate::message::{Message, MessageType};
 
 use std::str;
@@ -13,10 +13,22 @@ use log::info;
 
 use snow::{self, Builder, HandshakeState};
 
+use spake2::{Ed25519Group, Identity as PakeIdentity, Password, Spake2};
+
+use zeroize::Zeroizing;
+
 use async_std::net::TcpStream;
 use async_std::prelude::*;
+use async_std::task;
 
-use futures::future::TryFutureExt;
+/// The result of sharing a key with the server
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Outcome {
+    /// The client was authorized and may connect right away
+    Enrolled,
+    /// The client was queued until the server administrator approves it
+    Pending,
+}
 
 pub async fn receive_key(stream: &mut TcpStream, msg: &Message) -> Result<(), HermodError> {
     info!("Reciving new key");
@@ -24,43 +36,144 @@ pub async fn receive_key(stream: &mut TcpStream, msg: &Message) -> Result<(), He
         .local_private_key((*SERVER_CONFIG).get_private_key())
         .build_responder()?;
 
-    let id = recv_identity(stream, &mut noise, msg).await?;
+    let (id, code) = recv_identity(stream, &mut noise, msg).await?;
+    let enrolled = enroll(id, &code).await;
+    reply(stream, enrolled).await
+}
+
+/// Pair with a client that knows a code from `hermod server pair`. The code authenticates
+/// both sides through a SPAKE2 exchange, the resulting key is then mixed into the Noise
+/// handshake that shares the static keys.
+pub async fn receive_pairing(stream: &mut TcpStream, msg: &Message) -> Result<(), HermodError> {
+    info!("Pairing with a new client");
+    let (nameplate, outbound) = msg.get_payload().split_at(2);
+    let nameplate = u16::from_be_bytes([nameplate[0], nameplate[1]]);
+
+    // The pairing is used up before the exchange, a code only gets a single guess
+    let pairing = match task::spawn_blocking(move || invite::claim_pairing(nameplate)).await {
+        Ok(pairing) => pairing,
+        Err(e) => {
+            stream.write_all(&[MessageType::Error as u8]).await?;
+            return Err(e);
+        }
+    };
+    let (spake, inbound) = Spake2::<Ed25519Group>::start_b(
+        &Password::new(pairing.code.as_bytes()),
+        &PakeIdentity::new(PAIR_CLIENT_ID),
+        &PakeIdentity::new(PAIR_SERVER_ID),
+    );
+    let psk = spake
+        .finish(outbound)
+        .map(Zeroizing::new)
+        .map_err(|e| pairing_error(&e.to_string()))?;
+    stream.write_all(&[MessageType::PairResp as u8]).await?;
+    stream.write_all(&inbound).await?;
+
+    let mut noise = Builder::new(PAIR_PATTERN.parse()?)
+        .local_private_key((*SERVER_CONFIG).get_private_key())
+        .psk(2, &psk)
+        .build_responder()?;
+    let mut buf = vec![0u8; MSG_TYPE_LEN + HERMOD_KS_PSK_INIT_LEN];
+    stream.read_exact(&mut buf).await?;
+    if MessageType::from(buf[0]) != MessageType::ShareKeyInit {
+        return Err(HermodError::new(HermodErrorKind::OutOfOrderMessage));
+    }
+    let msg = Message::new(MessageType::ShareKeyInit, &buf[MSG_TYPE_LEN..]);
+
+    // A client with the wrong code can not complete the handshake
+    let (id, _) = recv_identity(stream, &mut noise, &msg)
+        .await
+        .map_err(|_| pairing_error("the client did not know the code"))?;
+    let enrolled = authorize(id.with_label(&pairing.label)).await;
+    reply(stream, enrolled).await
+}
+
+/// Tell the client the outcome of the enrollment, it is only told once the client is stored
+async fn reply(
+    stream: &mut TcpStream,
+    enrolled: Result<Outcome, HermodError>,
+) -> Result<(), HermodError> {
+    match enrolled {
+        Ok(Outcome::Enrolled) => {
+            stream.write_all(&[MessageType::Okay as u8]).await?;
+            Ok(())
+        }
+        Ok(Outcome::Pending) => {
+            stream.write_all(&[MessageType::Pending as u8]).await?;
+            Ok(())
+        }
+        Err(e) => {
+            stream.write_all(&[MessageType::Error as u8]).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Store a new client if the enrollment mode of the server allows it
+async fn enroll(id: Identity, code: &str) -> Result<Outcome, HermodError> {
+    let enrollment = SERVER_SETTINGS.read().unwrap().enrollment;
+    match enrollment {
+        Enrollment::Open => authorize(id).await,
+        Enrollment::Approval if code.is_empty() => {
+            info!("Client {} is awaiting approval", id.get_id());
+            task::spawn_blocking(move || identity::add_pending(id)).await?;
+            Ok(Outcome::Pending)
+        }
+        Enrollment::Invite | Enrollment::Approval => {
+            let code = code.to_owned();
+            let label = task::spawn_blocking(move || invite::redeem(&code)).await?;
+            authorize(id.with_label(&label)).await
+        }
+    }
+}
 
+/// Add a client to the authorized clients
+async fn authorize(id: Identity) -> Result<Outcome, HermodError> {
     identity::write_to_file(&id).await?;
+    info!("Enrolled client {}", id.get_id());
     KNOWN_CLIENTS
         .lock()
         .await
         .insert(id.get_id().to_owned(), id);
-    Ok(())
+    Ok(Outcome::Enrolled)
+}
+
+/// Options for sharing a key with a server
+#[derive(Debug, Default)]
+pub struct ShareKeyOptions<'a> {
+    /// Protect the new private key with a passphrase
+    pub passphrase: Option<&'a str>,
+    /// The fingerprint the server key must have, the user is asked to confirm the key if unset
+    pub fingerprint: Option<&'a str>,
+    /// Invitation code from `hermod server invite`
+    pub invite: Option<&'a str>,
+    /// Pairing code from `hermod server pair`
+    pub code: Option<&'a str>,
 }
 
-pub fn share_key(host: Host) {
+/// Share a new key pair with the server
+pub fn share_key(host: Host, options: &ShareKeyOptions) {
+    let invite = options.invite.unwrap_or_default();
+    if invite.len() > INVITE_CODE_LEN || invite.contains('\0') {
+        eprintln!("Invalid invitation code: {}", invite);
+        return;
+    }
+
+    let pairing = match options.code.map(invite::parse_pairing_code) {
+        Some(None) => {
+            eprintln!("Invalid pairing code: {}", options.code.unwrap_or_default());
+            return;
+        }
+        Some(pairing) => pairing,
+        None => None,
+    };
+
     let keys = genkey::gen_keys().expect("Failed to generate a static key pair");
     let id = genkey::gen_idtoken();
 
-    let mut noise = Builder::new(
-        SHARE_KEY_PATTERN
-            .parse()
-            .expect("Invalid Noise Pattern supplied"),
-    )
-    .local_private_key(&keys.private)
-    .build_initiator()
-    .expect("Failed to create noise sate machine");
-
     async_std::task::block_on(async move {
-        let socket_addr = (host.hostname(), HERMOD_PORT);
-
-        println!("Connecting to {:?}", socket_addr);
-
-        let server_key = match TcpStream::connect(socket_addr)
-            .err_into::<HermodError>()
-            .and_then(|stream| send_identity(stream, &mut noise, &id))
-            .await
-        {
-            Ok(_) => noise
-                .get_remote_static()
-                .expect("Failed to read the remotes public static key")
-                .to_vec(),
+        let (server_key, outcome) = match exchange_keys(&host, &keys, &id, options, pairing).await {
+            Ok(res) => res,
             Err(e) => {
                 eprintln!("Failed to share key with the server: {}", e);
                 return;
@@ -70,23 +183,119 @@ pub fn share_key(host: Host) {
         let res = host
             .set_id_token(&id)
             .set_server_key(&server_key)
-            .set_private_key(&keys.private)
+            .set_private_key(keys.private.as_bytes())
             .set_public_key(&keys.public)
-            .write_to_file()
-            .map_err(|err| HermodError::new(HermodErrorKind::IoError(err)));
+            .set_passphrase(options.passphrase)
+            .and_then(|host| {
+                host.write_to_file()
+                    .map_err(|err| HermodError::new(HermodErrorKind::IoError(err)))
+            });
 
         match res {
+            Ok(_) if outcome == Outcome::Pending => println!(
+                "Shared keys with the remote, the key can be used once the server administrator approves the id token {}",
+                id
+            ),
             Ok(_) => println!("Succesfully shared keys with the remote"),
             Err(e) => eprintln!("Failed to write configuration to file: {}", e),
         }
     });
 }
 
+/// Connect to the server and share the static keys, returns the public key of the server and
+/// whether the client was enrolled
+async fn exchange_keys(
+    host: &Host,
+    keys: &KeyPair,
+    token: &str,
+    options: &ShareKeyOptions<'_>,
+    pairing: Option<(u16, String)>,
+) -> Result<(Vec<u8>, Outcome), HermodError> {
+    let socket_addr = (host.hostname(), host.port());
+    println!("Connecting to {:?}", socket_addr);
+    let mut stream = TcpStream::connect(socket_addr).await?;
+
+    let psk = match pairing {
+        Some((nameplate, code)) => Some(pair(&mut stream, nameplate, &code).await?),
+        None => None,
+    };
+    let builder = match psk {
+        Some(ref psk) => Builder::new(PAIR_PATTERN.parse()?).psk(2, psk),
+        None => Builder::new(SHARE_KEY_PATTERN.parse()?),
+    };
+    let mut noise = builder
+        .local_private_key(keys.private.as_bytes())
+        .build_initiator()?;
+
+    let invite = options.invite.unwrap_or_default();
+    let outcome = send_identity(stream, &mut noise, token, invite, |key| {
+        match options.fingerprint {
+            // The server proved that it knows the pairing code, there is nothing to confirm
+            None if psk.is_some() => {
+                println!(
+                    "The fingerprint of the server key is {}",
+                    genkey::fingerprint(key)
+                );
+                Ok(())
+            }
+            expected => verify_server(key, expected),
+        }
+    })
+    .await
+    .map_err(|e| match e.kind() {
+        HermodErrorKind::Snow(_) if psk.is_some() => {
+            pairing_error("the server does not know the code, check that it was typed correctly")
+        }
+        _ => e,
+    })?;
+
+    let server_key = noise
+        .get_remote_static()
+        .expect("Failed to read the remotes public static key")
+        .to_vec();
+    Ok((server_key, outcome))
+}
+
+/// Agree on a key with the server through a SPAKE2 exchange with the pairing code
+async fn pair(
+    stream: &mut TcpStream,
+    nameplate: u16,
+    code: &str,
+) -> Result<Zeroizing<Vec<u8>>, HermodError> {
+    let (spake, outbound) = Spake2::<Ed25519Group>::start_a(
+        &Password::new(code.as_bytes()),
+        &PakeIdentity::new(PAIR_CLIENT_ID),
+        &PakeIdentity::new(PAIR_SERVER_ID),
+    );
+    stream.write_all(&[MessageType::PairInit as u8]).await?;
+    stream.write_all(&nameplate.to_be_bytes()).await?;
+    stream.write_all(&outbound).await?;
+
+    let mut buf = [0u8; HERMOD_PAIR_RESP_LEN];
+    stream.read_exact(&mut buf[..MSG_TYPE_LEN]).await?;
+    match MessageType::from(buf[0]) {
+        MessageType::PairResp => (),
+        MessageType::Error => return Err(pairing_error("the code is unknown or has expired")),
+        _ => return Err(HermodError::new(HermodErrorKind::OutOfOrderMessage)),
+    }
+    stream.read_exact(&mut buf[MSG_TYPE_LEN..]).await?;
+    spake
+        .finish(&buf[MSG_TYPE_LEN..])
+        .map(Zeroizing::new)
+        .map_err(|e| pairing_error(&e.to_string()))
+}
+
+fn pairing_error(msg: &str) -> HermodError {
+    HermodError::new(HermodErrorKind::Pairing(msg.to_owned()))
+}
+
+/// Conduct the handshake with a new client, returns the identity of the client and the
+/// invitation code it presented
 async fn recv_identity(
     stream: &mut TcpStream,
     noise: &mut HandshakeState,
     msg: &Message,
-) -> Result<identity::Identity, HermodError> {
+) -> Result<(Identity, String), HermodError> {
     // -> e
     noise.read_message(msg.get_payload(), &mut [])?;
     // <- e, s
@@ -94,14 +303,15 @@ async fn recv_identity(
     let len = noise.write_message(&[], &mut buf)?;
     stream.write_all(&[MessageType::ShareKeyResp as u8]).await?;
     stream.write_all(&buf[..len]).await?;
-    // -> s, id
+    // -> s, id, invitation code
     let mut buf = vec![0u8; HERMOD_KS_RES2_LEN];
-    let mut id = vec![0u8; ID_TOKEN_B64LEN as usize];
+    let mut payload = vec![0u8; ID_TOKEN_B64LEN as usize + INVITE_CODE_LEN];
     stream.read_exact(&mut buf).await?;
-    noise.read_message(&buf[MSG_TYPE_LEN..], &mut id)?;
+    noise.read_message(&buf[MSG_TYPE_LEN..], &mut payload)?;
 
-    let id = identity::Identity::new(
-        str::from_utf8(&id)
+    let (id, code) = payload.split_at(ID_TOKEN_B64LEN as usize);
+    let id = Identity::new(
+        str::from_utf8(id)
             .map_err(|_| HermodError::new(HermodErrorKind::Other))?
             .to_owned(),
         noise
@@ -109,15 +319,55 @@ async fn recv_identity(
             .expect("Failed to read the remotes public static key")
             .to_vec(),
     );
-    stream.write_all(&[MessageType::Okay as u8]).await?;
-    Ok(id)
+    // The code is padded with zeros to a fixed length
+    let code = str::from_utf8(code)
+        .map_err(|_| HermodError::new(HermodErrorKind::InvalidInvite))?
+        .trim_end_matches('\0')
+        .to_owned();
+    Ok((id, code))
+}
+
+/// Check the fingerprint of the server key against the expected one, or ask the user to
+/// confirm it
+fn verify_server(key: &[u8], expected: Option<&str>) -> Result<(), HermodError> {
+    let actual = genkey::fingerprint(key);
+    println!("The fingerprint of the server key is {}", actual);
+
+    match expected {
+        // Allow the fingerprint to be given without the prefix
+        Some(expected) if expected.trim_start_matches("SHA256:") == &actual["SHA256:".len()..] => {
+            Ok(())
+        }
+        Some(expected) => Err(HermodError::new(HermodErrorKind::Fingerprint(format!(
+            "expected {}, the server presented {}",
+            expected, actual
+        )))),
+        None => {
+            print!("Compare it to the output of 'hermod server fingerprint' on the server.\nAre you sure you want to continue (yes/no)? ");
+            std::io::Write::flush(&mut std::io::stdout())?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if answer.trim().eq_ignore_ascii_case("yes") {
+                Ok(())
+            } else {
+                Err(HermodError::new(HermodErrorKind::Fingerprint(
+                    "the server key was not accepted".to_owned(),
+                )))
+            }
+        }
+    }
 }
 
-async fn send_identity(
+async fn send_identity<F>(
     mut stream: TcpStream,
     noise: &mut HandshakeState,
     token: &str,
-) -> Result<(), HermodError> {
+    invite: &str,
+    verify: F,
+) -> Result<Outcome, HermodError>
+where
+    F: FnOnce(&[u8]) -> Result<(), HermodError>,
+{
     // -> e
     let mut buf = vec![0u8; 64];
     let len = noise.write_message(&[], &mut buf)?;
@@ -127,16 +377,27 @@ async fn send_identity(
     let mut buf = vec![0u8; HERMOD_KS_RES1_LEN];
     stream.read_exact(&mut buf).await?;
     noise.read_message(&buf[MSG_TYPE_LEN..], &mut [])?;
-    // -> s, id
+    // The identity is only sent once the server is known to be the right one
+    verify(
+        noise
+            .get_remote_static()
+            .expect("Failed to read the remotes public static key"),
+    )?;
+    // -> s, id, invitation code
+    let mut payload = token.as_bytes().to_vec();
+    payload.extend(invite.as_bytes());
+    payload.resize(ID_TOKEN_B64LEN as usize + INVITE_CODE_LEN, 0);
     let mut buf = vec![0u8; HERMOD_KS_RES2_LEN - MSG_TYPE_LEN];
-    let len = noise.write_message(token.as_bytes(), &mut buf)?;
+    let len = noise.write_message(&payload, &mut buf)?;
     stream.write_all(&[MessageType::ShareKeyResp as u8]).await?;
     stream.write_all(&buf[..len]).await?;
     // <- OK
     let mut buf = vec![0u8; MSG_TYPE_LEN];
     stream.read_exact(&mut buf).await?;
     match MessageType::from(buf[0]) {
-        MessageType::Okay => Ok(()),
+        MessageType::Okay => Ok(Outcome::Enrolled),
+        MessageType::Pending => Ok(Outcome::Pending),
+        MessageType::Error => Err(HermodError::new(HermodErrorKind::EnrollmentRejected)),
         _ => Err(HermodError::new(HermodErrorKind::ShareKey)),
     }
 }
@@ -0,0 +1,303 @@
+use crate::client::HermodClient;
+use crate::config::{ClientConfigBuilder, ClientSettings};
+use crate::error::{HermodError, HermodErrorKind, RemoteError, RemoteErrorKind};
+use crate::filter::Filter;
+use crate::host::Host;
+use crate::peer::Endpoint;
+use crate::request::{Request, RequestMethod};
+
+use std::cell::RefCell;
+use std::path::{Component, Path, PathBuf};
+
+use async_std::task;
+
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+const COMMANDS: &[&str] = &[
+    "cd", "exit", "get", "help", "lcd", "lpwd", "ls", "mkdir", "put", "pwd", "quit", "rm",
+];
+
+const HELP: &str = "\
+Available commands:
+    cd [PATH]               Change the remote directory
+    get REMOTE [LOCAL]      Download a file or directory
+    help                    Display this help
+    lcd PATH                Change the local directory
+    lpwd                    Print the local directory
+    ls [PATH]               List the content of a remote directory
+    mkdir PATH...           Create remote directories
+    put LOCAL [REMOTE]      Upload a file or directory
+    pwd                     Print the remote directory
+    rm [-r] PATH...         Remove remote files, or directories with -r
+    exit, quit              Close the connection";
+
+/// An authenticated connection to the server and the current remote directory
+struct Session {
+    endpoint: Endpoint,
+    remote_dir: PathBuf,
+}
+
+impl Session {
+    fn resolve(&self, path: &str) -> PathBuf {
+        normalize(&self.remote_dir.join(path))
+    }
+}
+
+struct ShellHelper {
+    session: RefCell<Session>,
+    files: FilenameCompleter,
+}
+
+impl ShellHelper {
+    fn complete_remote(&self, word: &str) -> Vec<Pair> {
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => word.split_at(i + 1),
+            None => ("", word),
+        };
+
+        let mut session = self.session.borrow_mut();
+        let path = session.resolve(dir);
+        let request = match Request::file(&path.to_string_lossy(), "", RequestMethod::List) {
+            Ok(request) => request,
+            Err(_) => return Vec::new(),
+        };
+        let entries = match task::block_on(request.list(&mut session.endpoint)) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        entries
+            .iter()
+            .map(|entry| entry.name())
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: format!("{}{}", dir, name),
+            })
+            .collect()
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let words: Vec<&str> = line[..pos].split_whitespace().collect();
+        let word_start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[word_start..pos];
+
+        // The index of the word being completed, 0 being the command
+        let index = if word.is_empty() {
+            words.len()
+        } else {
+            words.len() - 1
+        };
+
+        if index == 0 {
+            let commands = COMMANDS
+                .iter()
+                .filter(|cmd| cmd.starts_with(word))
+                .map(|cmd| Pair {
+                    display: cmd.to_string(),
+                    replacement: format!("{} ", cmd),
+                })
+                .collect();
+            return Ok((word_start, commands));
+        }
+
+        match (words[0], index) {
+            ("lcd", _) | ("put", 1) | ("get", 2) => self.files.complete(line, pos, ctx),
+            _ => Ok((word_start, self.complete_remote(word))),
+        }
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Open an interactive session with the server, reusing a single connection for every command
+pub fn run(host: &Host, settings: &ClientSettings) -> Result<(), HermodError> {
+    let builder = ClientConfigBuilder::new(host)
+        .settings(settings)
+        .source(&[])
+        .request(RequestMethod::List);
+    let mut endpoint = task::block_on(HermodClient::new(builder.build_config()).connect())?;
+    endpoint.set_rate_limit(settings.rate_limit);
+
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper {
+        session: RefCell::new(Session {
+            endpoint,
+            remote_dir: PathBuf::from(settings.remote_dir.as_deref().unwrap_or(".")),
+        }),
+        files: FilenameCompleter::new(),
+    }));
+
+    println!(
+        "Connected to {}, type 'help' for a list of commands",
+        host.alias()
+    );
+
+    loop {
+        let prompt = {
+            let session = editor.helper().unwrap().session.borrow();
+            format!("hermod:{}:{}> ", host.alias(), session.remote_dir.display())
+        };
+
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Failed to read input: {}", e);
+                break;
+            }
+        };
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.as_str());
+
+        if args[0] == "exit" || args[0] == "quit" {
+            break;
+        }
+
+        let mut session = editor.helper().unwrap().session.borrow_mut();
+        match task::block_on(exec_command(&mut session, &args)) {
+            Ok(()) => (),
+            Err(e) if e.is_connection_error() => return Err(e),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    let mut session = editor.helper().unwrap().session.borrow_mut();
+    task::block_on(session.endpoint.close())
+}
+
+async fn exec_command(session: &mut Session, args: &[&str]) -> Result<(), HermodError> {
+    match args {
+        ["help"] => println!("{}", HELP),
+        ["pwd"] => println!("{}", session.remote_dir.display()),
+        ["lpwd"] => println!("{}", std::env::current_dir()?.display()),
+        ["lcd", path] => std::env::set_current_dir(path)?,
+        ["cd"] => session.remote_dir = PathBuf::from("."),
+        ["cd", path] => {
+            let path = session.resolve(path);
+            let stat = Request::file(&path.to_string_lossy(), "", RequestMethod::Stat)?
+                .stat(&mut session.endpoint)
+                .await?;
+            if !stat.dir {
+                let msg = format!("{} is not a directory", path.display());
+                let err = RemoteError::new(RemoteErrorKind::InvalidRequest, &msg);
+                return Err(HermodError::new(HermodErrorKind::Remote(err)));
+            }
+            session.remote_dir = path;
+        }
+        ["ls"] | ["ls", _] => {
+            let path = session.resolve(args.get(1).unwrap_or(&"."));
+            let entries = Request::file(&path.to_string_lossy(), "", RequestMethod::List)?
+                .list(&mut session.endpoint)
+                .await?;
+            for entry in entries {
+                println!("{}", entry.name());
+            }
+        }
+        ["get", source] | ["get", source, _] => {
+            let source = session.resolve(source);
+            let destination = args.get(2).unwrap_or(&".");
+            Request::file(
+                &source.to_string_lossy(),
+                destination,
+                RequestMethod::Download,
+            )?
+            .exec(&mut session.endpoint)
+            .await?;
+        }
+        ["put", source] | ["put", source, _] => {
+            let destination = session.resolve(args.get(2).unwrap_or(&"."));
+            let destination = destination.to_string_lossy();
+            let requests = if Path::new(source).is_dir() {
+                Request::dir(
+                    source,
+                    &destination,
+                    RequestMethod::Upload,
+                    &Filter::default(),
+                )?
+            } else {
+                vec![Request::file(source, &destination, RequestMethod::Upload)?]
+            };
+            Request::exec_all(&mut session.endpoint, &requests).await?;
+        }
+        ["mkdir", paths @ ..] if !paths.is_empty() => {
+            exec_all(session, paths, RequestMethod::Mkdir).await?
+        }
+        ["rm", "-r", paths @ ..] if !paths.is_empty() => {
+            exec_all(session, paths, RequestMethod::RemoveAll).await?
+        }
+        ["rm", paths @ ..] if !paths.is_empty() => {
+            exec_all(session, paths, RequestMethod::Remove).await?
+        }
+        _ => eprintln!(
+            "Invalid command: '{}', type 'help' for usage",
+            args.join(" ")
+        ),
+    }
+    Ok(())
+}
+
+async fn exec_all(
+    session: &mut Session,
+    paths: &[&str],
+    method: RequestMethod,
+) -> Result<(), HermodError> {
+    for path in paths {
+        let path = session.resolve(path);
+        Request::file(&path.to_string_lossy(), "", method)?
+            .exec(&mut session.endpoint)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Lexically resolve '.' and '..' in a remote path, the server resolves everything else
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => (),
+                _ => normalized.push(".."),
+            },
+            _ => normalized.push(component),
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        normalized
+    }
+}
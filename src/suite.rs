@@ -0,0 +1,159 @@
+use crate::hacl::HaclResolver;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use snow::params::{CipherChoice, DHChoice, HashChoice, NoiseParams};
+use snow::resolvers::{CryptoResolver, DefaultResolver, FallbackResolver};
+
+const DH_FUNCTIONS: [(DHChoice, &str); 2] =
+    [(DHChoice::Curve25519, "25519"), (DHChoice::Ed448, "448")];
+const CIPHERS: [(CipherChoice, &str); 2] = [
+    (CipherChoice::ChaChaPoly, "ChaChaPoly"),
+    (CipherChoice::AESGCM, "AESGCM"),
+];
+const HASHES: [(HashChoice, &str); 4] = [
+    (HashChoice::Blake2s, "BLAKE2s"),
+    (HashChoice::Blake2b, "BLAKE2b"),
+    (HashChoice::SHA256, "SHA256"),
+    (HashChoice::SHA512, "SHA512"),
+];
+
+/// The Diffie-Hellman function, cipher and hash used for a Noise handshake, written like in
+/// the name of the protocol, e.g. `25519_ChaChaPoly_BLAKE2s`.
+/// Sent as a single byte when negotiating the suite of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Suite {
+    dh: usize,
+    cipher: usize,
+    hash: usize,
+}
+
+impl Suite {
+    /// The parameters of the handshake pattern `pattern`, e.g. `IK`, with this suite
+    pub fn params(&self, pattern: &str) -> NoiseParams {
+        format!("Noise_{}_{}", pattern, self)
+            .parse()
+            .expect("Every suite forms a valid protocol name")
+    }
+
+    /// Whether this build can use the suite, the cryptographic primitives must be implemented
+    /// and the keys of hosts and clients are X25519 keys
+    pub fn is_available(&self) -> bool {
+        let resolver = resolver();
+        DH_FUNCTIONS[self.dh].0 == DHChoice::Curve25519
+            && resolver.resolve_dh(&DH_FUNCTIONS[self.dh].0).is_some()
+            && resolver.resolve_cipher(&CIPHERS[self.cipher].0).is_some()
+            && resolver.resolve_hash(&HASHES[self.hash].0).is_some()
+    }
+
+    pub fn to_byte(self) -> u8 {
+        (self.dh * CIPHERS.len() * HASHES.len() + self.cipher * HASHES.len() + self.hash) as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        let byte = byte as usize;
+        let suite = Suite {
+            dh: byte / (CIPHERS.len() * HASHES.len()),
+            cipher: byte / HASHES.len() % CIPHERS.len(),
+            hash: byte % HASHES.len(),
+        };
+        Some(suite).filter(|suite| suite.dh < DH_FUNCTIONS.len())
+    }
+}
+
+impl Default for Suite {
+    /// 25519, ChaChaPoly and BLAKE2s, the suite used before suites were negotiated
+    fn default() -> Self {
+        Suite {
+            dh: 0,
+            cipher: 0,
+            hash: 0,
+        }
+    }
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}_{}_{}",
+            DH_FUNCTIONS[self.dh].1, CIPHERS[self.cipher].1, HASHES[self.hash].1
+        )
+    }
+}
+
+impl FromStr for Suite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('_');
+        let suite = match (
+            position(&DH_FUNCTIONS, parts.next()),
+            position(&CIPHERS, parts.next()),
+            position(&HASHES, parts.next()),
+            parts.next(),
+        ) {
+            (Some(dh), Some(cipher), Some(hash), None) => Suite { dh, cipher, hash },
+            _ => return Err(format!("invalid cipher suite '{}'", s)),
+        };
+        if !suite.is_available() {
+            return Err(format!(
+                "the cipher suite {} is not supported by this build",
+                s
+            ));
+        }
+        Ok(suite)
+    }
+}
+
+impl TryFrom<String> for Suite {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The suite to use with a client offering `offered`, the first of `preferred` that is offered
+pub fn choose(preferred: &[Suite], offered: &[Suite]) -> Option<Suite> {
+    preferred
+        .iter()
+        .find(|suite| offered.contains(suite))
+        .copied()
+}
+
+fn position<T>(table: &[(T, &str)], name: Option<&str>) -> Option<usize> {
+    name.and_then(|name| table.iter().position(|(_, n)| *n == name))
+}
+
+/// Resolves the primitives of a suite, with the HACL implementations where there are any
+pub fn resolver() -> Box<dyn CryptoResolver> {
+    Box::new(FallbackResolver::new(
+        Box::new(HaclResolver),
+        Box::new(DefaultResolver),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_choose_suites() {
+        let default: Suite = "25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        let sha256: Suite = "25519_ChaChaPoly_SHA256".parse().unwrap();
+        assert_eq!(default, Suite::default());
+        assert_eq!(sha256.to_string(), "25519_ChaChaPoly_SHA256");
+        assert_eq!(Suite::from_byte(sha256.to_byte()), Some(sha256));
+        assert!("25519_ChaChaPoly".parse::<Suite>().is_err());
+        assert!("448_ChaChaPoly_SHA256".parse::<Suite>().is_err());
+
+        assert_eq!(choose(&[sha256, default], &[default, sha256]), Some(sha256));
+        assert_eq!(choose(&[sha256], &[default]), None);
+    }
+}